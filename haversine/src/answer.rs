@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+/// Answer file layout, all values big-endian:
+///
+/// | offset | size | field                             |
+/// |--------|------|-----------------------------------|
+/// | 0      | 8    | magic `HAVANSWR`                  |
+/// | 8      | 2    | version                           |
+/// | 10     | 1    | distribution                      |
+/// | 11     | 1    | flags (bit 0: seed is present)    |
+/// | 12     | 4    | reserved                          |
+/// | 16     | 8    | pair count                        |
+/// | 24     | 8    | random seed                       |
+/// | 32     | 8    | earth radius (f64)                |
+/// | 40     | 8    | haversine sum, the mean (f64)     |
+/// | 48     | 8*n  | per-pair distances (f64)          |
+///
+/// Legacy files are just the per-pair distances followed by the sum.
+pub const ANSWER_MAGIC: [u8; 8] = *b"HAVANSWR";
+pub const ANSWER_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 48;
+const FLAG_HAS_SEED: u8 = 1;
+
+/// The radius every legacy answer file was generated with.
+const LEGACY_EARTH_RADIUS: f64 = 6372.8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Distribution {
+    Unknown = 0,
+    Uniform = 1,
+    Cluster = 2,
+}
+
+impl Distribution {
+    pub fn name(self) -> &'static str {
+        match self {
+            Distribution::Unknown => "unknown",
+            Distribution::Uniform => "uniform",
+            Distribution::Cluster => "cluster",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Distribution> {
+        match value {
+            0 => Some(Distribution::Unknown),
+            1 => Some(Distribution::Uniform),
+            2 => Some(Distribution::Cluster),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnswerFile {
+    /// 0 for legacy headerless files.
+    pub version: u16,
+    pub seed: Option<u64>,
+    pub distribution: Distribution,
+    pub earth_radius: f64,
+    pub sum: f64,
+    pub distances: Vec<f64>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_be_f64(bytes: &[u8]) -> f64 {
+    f64::from_be_bytes(bytes.try_into().unwrap())
+}
+
+impl AnswerFile {
+    pub fn new(distances: Vec<f64>, sum: f64, earth_radius: f64) -> Self {
+        AnswerFile {
+            version: ANSWER_VERSION,
+            seed: None,
+            distribution: Distribution::Unknown,
+            earth_radius,
+            sum,
+            distances,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<AnswerFile> {
+        if !bytes.starts_with(&ANSWER_MAGIC) {
+            return Self::from_legacy_bytes(bytes);
+        }
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid_data(format!("Answer file header is truncated ({} bytes)", bytes.len())));
+        }
+
+        let version = u16::from_be_bytes(bytes[8..10].try_into().unwrap());
+        if version != ANSWER_VERSION {
+            return Err(invalid_data(format!("Unsupported answer file version {version}")));
+        }
+
+        let distribution = Distribution::from_u8(bytes[10])
+            .ok_or_else(|| invalid_data(format!("Unknown distribution {}", bytes[10])))?;
+        let flags = bytes[11];
+        let count = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
+        let seed = u64::from_be_bytes(bytes[24..32].try_into().unwrap());
+        let earth_radius = read_be_f64(&bytes[32..40]);
+        let sum = read_be_f64(&bytes[40..48]);
+
+        let payload = &bytes[HEADER_SIZE..];
+        if !payload.len().is_multiple_of(size_of::<f64>()) || (payload.len() / size_of::<f64>()) as u64 != count {
+            return Err(invalid_data(format!("Answer file header says {count} pairs but has {} bytes of distances", payload.len())));
+        }
+
+        Ok(AnswerFile {
+            version,
+            seed: (flags & FLAG_HAS_SEED != 0).then_some(seed),
+            distribution,
+            earth_radius,
+            sum,
+            distances: payload.chunks_exact(size_of::<f64>()).map(read_be_f64).collect(),
+        })
+    }
+
+    fn from_legacy_bytes(bytes: &[u8]) -> io::Result<AnswerFile> {
+        if bytes.len() < size_of::<f64>() || !bytes.len().is_multiple_of(size_of::<f64>()) {
+            return Err(invalid_data(format!("Answer file size {} is not a whole number of f64s", bytes.len())));
+        }
+
+        let ref_sum_idx = bytes.len() - size_of::<f64>();
+        Ok(AnswerFile {
+            version: 0,
+            seed: None,
+            distribution: Distribution::Unknown,
+            earth_radius: LEGACY_EARTH_RADIUS,
+            sum: read_be_f64(&bytes[ref_sum_idx..]),
+            distances: bytes[..ref_sum_idx].chunks_exact(size_of::<f64>()).map(read_be_f64).collect(),
+        })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<AnswerFile> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Always writes the current version, even if this was read from a legacy file.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(&ANSWER_MAGIC);
+        header[8..10].copy_from_slice(&ANSWER_VERSION.to_be_bytes());
+        header[10] = self.distribution as u8;
+        header[11] = if self.seed.is_some() { FLAG_HAS_SEED } else { 0 };
+        header[16..24].copy_from_slice(&(self.distances.len() as u64).to_be_bytes());
+        header[24..32].copy_from_slice(&self.seed.unwrap_or(0).to_be_bytes());
+        header[32..40].copy_from_slice(&self.earth_radius.to_be_bytes());
+        header[40..48].copy_from_slice(&self.sum.to_be_bytes());
+        out.write_all(&header)?;
+
+        for distance in &self.distances {
+            out.write_all(&distance.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut answers = AnswerFile::new(vec![1.5, 2.5, 1000.25], 334.75, 6372.8);
+        answers.seed = Some(1234);
+        answers.distribution = Distribution::Cluster;
+
+        let mut bytes = Vec::new();
+        answers.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), HEADER_SIZE + 3 * size_of::<f64>());
+        assert_eq!(AnswerFile::from_bytes(&bytes).unwrap(), answers);
+
+        // Truncated distances
+        assert!(AnswerFile::from_bytes(&bytes[..bytes.len() - 8]).is_err());
+        assert!(AnswerFile::from_bytes(&bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn legacy() {
+        let bytes = [1.5f64, 2.5, 2.0].iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+        let answers = AnswerFile::from_bytes(&bytes).unwrap();
+        assert!(answers.is_legacy());
+        assert_eq!(answers.distances, [1.5, 2.5]);
+        assert_eq!(answers.sum, 2.0);
+        assert_eq!(answers.seed, None);
+
+        assert!(AnswerFile::from_bytes(&[]).is_err());
+        assert!(AnswerFile::from_bytes(&bytes[..5]).is_err());
+    }
+}
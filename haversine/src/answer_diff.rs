@@ -0,0 +1,225 @@
+use haversine::answer::AnswerFile;
+use haversine::compare::{ulp_distance, WorstList};
+use haversine::json::{object, Value};
+use haversine::stats::QuantileSketch;
+use haversine::time_function;
+
+use crate::cli::{Error, Options, ReportFormat};
+use crate::{read_answers, Outcome};
+
+const PERCENTILES: [f64; 6] = [50.0, 90.0, 99.0, 99.9, 99.99, 100.0];
+const SKETCH_ALPHA: f64 = 0.001;
+const HISTOGRAM_BAR_WIDTH: u64 = 40;
+/// Bucket 0 is exact matches, bucket k holds ULP distances in [2^(k-1), 2^k).
+const ULP_BUCKET_COUNT: usize = u64::BITS as usize + 1;
+
+struct Offender {
+    index: usize,
+    a: f64,
+    b: f64,
+    relative_error: f64,
+    ulps: u64,
+}
+
+struct AnswerDiff {
+    compared: usize,
+    a_count: usize,
+    b_count: usize,
+    exact: usize,
+    max_abs_error: f64,
+    max_relative_error: f64,
+    max_ulps: u64,
+    ulp_sketch: QuantileSketch,
+    ulp_buckets: [u64; ULP_BUCKET_COUNT],
+    worst: WorstList<Offender>,
+}
+
+fn ulp_bucket(ulps: u64) -> usize {
+    (u64::BITS - ulps.leading_zeros()) as usize
+}
+
+/// Range of ULP distances in a bucket, as printed.
+fn ulp_bucket_label(bucket: usize) -> String {
+    match bucket {
+        0 => "0".to_string(),
+        1 => "1".to_string(),
+        _ => format!("{}-{}", 1u64 << (bucket - 1), u64::MAX >> (u64::BITS as usize - bucket)),
+    }
+}
+
+/// Compares `a` against `b` pair by pair, treating `b` as the reference for relative errors.
+fn diff_answers(a: &AnswerFile, b: &AnswerFile, worst_count: usize) -> AnswerDiff {
+    time_function!();
+
+    let mut diff = AnswerDiff {
+        compared: a.distances.len().min(b.distances.len()),
+        a_count: a.distances.len(),
+        b_count: b.distances.len(),
+        exact: 0,
+        max_abs_error: 0.0,
+        max_relative_error: 0.0,
+        max_ulps: 0,
+        ulp_sketch: QuantileSketch::new(SKETCH_ALPHA),
+        ulp_buckets: [0; ULP_BUCKET_COUNT],
+        worst: WorstList::new(worst_count),
+    };
+
+    for (index, (&a, &b)) in a.distances.iter().zip(&b.distances).enumerate() {
+        let abs_error = (a - b).abs();
+        let relative_error = if abs_error == 0.0 { 0.0 } else { abs_error / b.abs() };
+        let ulps = ulp_distance(a, b);
+
+        diff.exact += (ulps == 0) as usize;
+        diff.max_abs_error = diff.max_abs_error.max(abs_error);
+        diff.max_relative_error = diff.max_relative_error.max(relative_error);
+        diff.max_ulps = diff.max_ulps.max(ulps);
+        diff.ulp_sketch.push(ulps as f64);
+        diff.ulp_buckets[ulp_bucket(ulps)] += 1;
+
+        if ulps != 0 {
+            diff.worst.push(abs_error, Offender { index, a, b, relative_error, ulps });
+        }
+    }
+
+    diff
+}
+
+fn describe(answers: &AnswerFile) -> String {
+    if answers.is_legacy() {
+        return format!("legacy, {} pairs", answers.distances.len());
+    }
+    let seed = answers.seed.map(|seed| seed.to_string()).unwrap_or("none".to_string());
+    format!("v{}, {} distribution, seed {seed}, {} pairs", answers.version, answers.distribution.name(), answers.distances.len())
+}
+
+fn print_diff(diff: &AnswerDiff, a: &AnswerFile, b: &AnswerFile) {
+    println!("Sum difference: {:.16} ({} ULP)", a.sum - b.sum, ulp_distance(a.sum, b.sum));
+    if a.earth_radius != b.earth_radius {
+        println!("WARNING: earth radius differs, {} vs {}.", a.earth_radius, b.earth_radius);
+    }
+    if diff.a_count != diff.b_count {
+        println!("WARNING: pair count differs, only the first {} pairs are compared.", diff.compared);
+    }
+
+    println!();
+    println!("Pairs compared: {}", diff.compared);
+    println!("Exact matches: {}", diff.exact);
+    println!("Max abs error: {:e}", diff.max_abs_error);
+    println!("Max relative error: {:e}", diff.max_relative_error);
+    println!("Max ULP: {}", diff.max_ulps);
+
+    println!();
+    println!("ULP percentiles (within {}%):", diff.ulp_sketch.alpha() * 100.0);
+    for percentile in PERCENTILES {
+        if let Some(ulps) = diff.ulp_sketch.quantile(percentile / 100.0) {
+            println!("  p{percentile}: {ulps:.0}");
+        }
+    }
+
+    println!();
+    println!("ULP histogram:");
+    let max_count = diff.ulp_buckets.iter().copied().max().unwrap_or(0).max(1);
+    let last = diff.ulp_buckets.iter().rposition(|&count| count != 0).unwrap_or(0);
+    for (bucket, &count) in diff.ulp_buckets.iter().enumerate().take(last + 1) {
+        let bar = "#".repeat((count * HISTOGRAM_BAR_WIDTH).div_ceil(max_count) as usize);
+        println!("  {:>24}: {count:>10} {bar}", ulp_bucket_label(bucket));
+    }
+
+    let mut worst = diff.worst.iter().peekable();
+    if worst.peek().is_some() {
+        println!();
+        println!("Worst pairs:");
+        for (abs_error, Offender { index, a, b, relative_error, ulps }) in worst {
+            println!("  [{index}] {a:.16} vs {b:.16} (error {abs_error:e}, relative {relative_error:e}, {ulps} ULP)");
+        }
+    }
+}
+
+fn diff_json(diff: &AnswerDiff, a: &AnswerFile, b: &AnswerFile) -> Vec<(String, Value)> {
+    let percentiles = PERCENTILES.iter().map(|&percentile| object([
+        ("percentile", percentile.into()),
+        ("ulps", diff.ulp_sketch.quantile(percentile / 100.0).into()),
+    ]));
+    let last = diff.ulp_buckets.iter().rposition(|&count| count != 0).unwrap_or(0);
+    let histogram = diff.ulp_buckets.iter().enumerate().take(last + 1).map(|(bucket, &count)| object([
+        ("ulps", ulp_bucket_label(bucket).into()),
+        ("count", count.into()),
+    ]));
+    let worst = diff.worst.iter().map(|(abs_error, offender)| object([
+        ("index", offender.index.into()),
+        ("a", offender.a.into()),
+        ("b", offender.b.into()),
+        ("abs_error", (*abs_error).into()),
+        ("relative_error", offender.relative_error.into()),
+        ("ulps", offender.ulps.into()),
+    ]));
+
+    let members = [
+        ("sum_a", a.sum.into()),
+        ("sum_b", b.sum.into()),
+        ("sum_difference", (a.sum - b.sum).into()),
+        ("pair_count_a", diff.a_count.into()),
+        ("pair_count_b", diff.b_count.into()),
+        ("compared", diff.compared.into()),
+        ("exact", diff.exact.into()),
+        ("max_abs_error", diff.max_abs_error.into()),
+        ("max_relative_error", diff.max_relative_error.into()),
+        ("max_ulps", diff.max_ulps.into()),
+        ("ulp_percentiles", Value::Array(percentiles.collect())),
+        ("ulp_histogram", Value::Array(histogram.collect())),
+        ("worst", Value::Array(worst.collect())),
+    ];
+    members.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+/// Compares two answer files. The first is the input path, the second the answer path.
+pub fn diff(options: &Options) -> Result<Outcome, Error> {
+    let a_path = options.input_path.as_str();
+    let b_path = options.answer_path.as_deref().unwrap();
+    let a = read_answers(a_path)?;
+    let b = read_answers(b_path)?;
+
+    let diff = diff_answers(&a, &b, options.worst_count);
+
+    if options.report == ReportFormat::Text {
+        println!("A: {a_path} ({})", describe(&a));
+        println!("B: {b_path} ({})", describe(&b));
+        print_diff(&diff, &a, &b);
+        println!();
+    }
+
+    let mut report = vec![
+        ("command".to_string(), options.command.name().into()),
+        ("a".to_string(), a_path.into()),
+        ("b".to_string(), b_path.into()),
+    ];
+    report.extend(diff_json(&diff, &a, &b));
+    Ok(Outcome { report: Value::Object(report), passed: true })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulp_buckets() {
+        assert_eq!(ulp_bucket(0), 0);
+        assert_eq!(ulp_bucket(1), 1);
+        assert_eq!((ulp_bucket(2), ulp_bucket(3), ulp_bucket(4)), (2, 2, 3));
+        assert_eq!(ulp_bucket(u64::MAX), ULP_BUCKET_COUNT - 1);
+        assert_eq!(ulp_bucket_label(3), "4-7");
+        assert_eq!(ulp_bucket_label(64), format!("{}-{}", 1u64 << 63, u64::MAX));
+    }
+
+    #[test]
+    fn diff_counts() {
+        let next = |x: f64, ulps: u64| f64::from_bits(x.to_bits() + ulps);
+        let a = AnswerFile::new(vec![1.0, 2.0, next(3.0, 5), 4.0], 2.5, 6372.8);
+        let b = AnswerFile::new(vec![1.0, next(2.0, 1), 3.0], 2.0, 6372.8);
+
+        let diff = diff_answers(&a, &b, 1);
+        assert_eq!((diff.compared, diff.exact, diff.max_ulps), (3, 1, 5));
+        assert_eq!(diff.ulp_buckets[..4], [1, 1, 0, 1]);
+        assert_eq!(diff.worst.iter().map(|(_, offender)| offender.index).collect::<Vec<_>>(), [2]);
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::{self, File};
+use std::hint::black_box;
+use std::io::{self, Read};
+use std::mem::size_of_val;
+use std::path::Path;
+
+use haversine::json::{self, object, Value};
+use haversine::Pair;
+use metrics::output::OutputFormat;
+use metrics::repetition_tester::{test_block, RepetitionTestResult, RepetitionTestValue, RepetitionTester};
+use metrics::timing::tsc_info;
+
+use crate::cli::{AllocMode, Error, Options, ReportFormat};
+use crate::{parse_input_text_into, Outcome, EARTH_RADIUS};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Stage {
+    Read,
+    Parse,
+    Sum,
+    Pipeline,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Read, Stage::Parse, Stage::Sum, Stage::Pipeline];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Parse => "parse",
+            Stage::Sum => "sum",
+            Stage::Pipeline => "pipeline",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Stage> {
+        Stage::ALL.into_iter().find(|stage| stage.name() == name)
+    }
+
+    /// Sum only reads the parsed pairs, so it runs once whatever the alloc modes are.
+    fn allocates(self) -> bool {
+        self != Stage::Sum
+    }
+}
+
+/// Buffers carried between repetitions. Fresh mode replaces them before every run, outside the timed block.
+#[derive(Default)]
+struct Buffers {
+    text: String,
+    pairs: Vec<Pair>,
+}
+
+struct StageResult {
+    stage: Stage,
+    alloc: Option<AllocMode>,
+    bytes: u64,
+    result: RepetitionTestResult,
+}
+
+fn read_into(path: &Path, text: &mut String) -> io::Result<()> {
+    text.clear();
+    File::open(path)?.read_to_string(text)?;
+    Ok(())
+}
+
+fn run_stage(tester: &mut RepetitionTester, mut test: impl FnMut(&mut RepetitionTester) -> Result<u64, Error>) -> Result<(), Error> {
+    while tester.testing() {
+        match test(tester) {
+            Ok(bytes) => tester.count_bytes(bytes),
+            Err(e) => {
+                tester.error(&e.to_string());
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// One value as the repetition tester writes it, so both reports agree, with nulls where the CPU
+/// frequency is unknown.
+fn value_json(value: &RepetitionTestValue, label: &str, cpu_freq: u64) -> Value {
+    let mut out = Vec::new();
+    value.write(&mut out, label, cpu_freq, OutputFormat::Json).expect("writing to a Vec");
+    let text = String::from_utf8(out).expect("the writer escapes labels");
+    json::parse(&text).expect("the writer writes valid JSON")
+}
+
+/// Repetition tests each selected stage of the pipeline on the real input file.
+pub fn bench(options: &Options) -> Result<Outcome, Error> {
+    let path = Path::new(&options.input_path);
+    let io_error = |e| Error::Io(options.input_path.clone(), e);
+
+    // Parse once up front, so malformed input fails before testing and later stages have their inputs
+    let text = fs::read_to_string(path).map_err(io_error)?;
+    let mut pairs = Vec::new();
+    let (format, _) = parse_input_text_into(path, &text, options.input_format, &mut pairs)?;
+
+    let tsc = tsc_info();
+    let cpu_freq = tsc.frequency.hz;
+    let text_report = options.report == ReportFormat::Text;
+
+    if text_report {
+        println!("Input: {} ({format}, {} bytes, {} pairs)", options.input_path, text.len(), pairs.len());
+        println!("Math: {}, {} threads", options.backend.name(), options.threads);
+        for warning in tsc.warnings() {
+            println!("WARNING: {warning}");
+        }
+    }
+
+    let mut results = Vec::new();
+    for &stage in &options.stages {
+        let alloc_modes = if stage.allocates() { options.alloc_modes.iter().copied().map(Some).collect() } else { vec![None] };
+
+        for alloc in alloc_modes {
+            let bytes = match stage {
+                Stage::Sum => size_of_val(pairs.as_slice()),
+                _ => text.len(),
+            } as u64;
+
+            if text_report {
+                match alloc {
+                    Some(alloc) => println!("\n--- {}, {} buffers ---", stage.name(), alloc.name()),
+                    None => println!("\n--- {} ---", stage.name()),
+                }
+            }
+
+            let mut tester = RepetitionTester::new(bytes, cpu_freq);
+            tester.print_new_minimums = text_report;
+            tester.print_results = text_report;
+            tester.new_test_wave(bytes, cpu_freq, options.seconds);
+
+            let fresh = alloc == Some(AllocMode::Fresh);
+            let mut buffers = Buffers::default();
+            match stage {
+                Stage::Read => run_stage(&mut tester, |tester| {
+                    if fresh {
+                        buffers = Buffers::default();
+                    }
+                    test_block!(tester);
+                    read_into(path, &mut buffers.text).map_err(io_error)?;
+                    Ok(buffers.text.len() as u64)
+                }),
+                Stage::Parse => run_stage(&mut tester, |tester| {
+                    if fresh {
+                        buffers = Buffers::default();
+                    }
+                    test_block!(tester);
+                    parse_input_text_into(path, &text, options.input_format, &mut buffers.pairs)?;
+                    Ok(text.len() as u64)
+                }),
+                Stage::Sum => run_stage(&mut tester, |tester| {
+                    test_block!(tester);
+                    black_box(options.backend.sum_distances_parallel(&pairs, EARTH_RADIUS, options.threads));
+                    Ok(bytes)
+                }),
+                Stage::Pipeline => run_stage(&mut tester, |tester| {
+                    if fresh {
+                        buffers = Buffers::default();
+                    }
+                    test_block!(tester);
+                    read_into(path, &mut buffers.text).map_err(io_error)?;
+                    parse_input_text_into(path, &buffers.text, options.input_format, &mut buffers.pairs)?;
+                    black_box(options.backend.sum_distances_parallel(&buffers.pairs, EARTH_RADIUS, options.threads));
+                    Ok(buffers.text.len() as u64)
+                }),
+            }?;
+
+            results.push(StageResult { stage, alloc, bytes, result: *tester.get_result() });
+        }
+    }
+
+    let stages = results.iter().map(|StageResult { stage, alloc, bytes, result }| object([
+        ("stage", stage.name().into()),
+        ("alloc", alloc.map(|alloc| alloc.name()).into()),
+        ("bytes", (*bytes).into()),
+        ("tests", result.total.num_tests.into()),
+        ("min", value_json(&result.min, "Min", cpu_freq)),
+        ("max", value_json(&result.max, "Max", cpu_freq)),
+        ("avg", value_json(&result.total, "Avg", cpu_freq)),
+    ]));
+
+    let report = object([
+        ("command", options.command.name().into()),
+        ("input", options.input_path.as_str().into()),
+        ("format", format.into()),
+        ("input_size", text.len().into()),
+        ("pair_count", pairs.len().into()),
+        ("math", options.backend.name().into()),
+        ("threads", options.threads.into()),
+        ("cpu_freq", cpu_freq.into()),
+        ("cpu_freq_source", tsc.frequency.source.name().into()),
+        ("stages", Value::Array(stages.collect())),
+    ]);
+
+    Ok(Outcome { report, passed: true })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_args;
+
+    #[test]
+    fn short_wave_reports_the_selected_stages() {
+        let input = r#"{"pairs":[{"x0":1,"y0":2,"x1":3,"y1":4},{"x0":-1.5,"y0":2,"x1":3,"y1":-4}]}"#;
+        let path = std::env::temp_dir().join(format!("haversine_bench_{}.json", std::process::id()));
+        fs::write(&path, input).unwrap();
+
+        let args = ["bench", path.to_str().unwrap(), "--seconds", "1", "--stage", "read,sum", "--alloc", "both", "--report", "json"];
+        let Ok(options) = parse_args(&args.map(String::from)) else { panic!("bad arguments") };
+        let outcome = bench(&options);
+        fs::remove_file(&path).unwrap();
+        let Ok(Outcome { report, passed }) = outcome else { panic!("bench failed") };
+        assert!(passed);
+
+        let stages = report.get("stages").and_then(Value::as_array).unwrap();
+        let found = stages.iter().map(|stage| {
+            let field = |key| stage.get(key).and_then(Value::as_str);
+            (field("stage").unwrap(), field("alloc"), stage.get("bytes").and_then(Value::as_f64).unwrap())
+        }).collect::<Vec<_>>();
+
+        let text_bytes = input.len() as f64;
+        let pair_bytes = (2 * size_of::<Pair>()) as f64;
+        assert_eq!(found, [
+            ("read", Some("reuse"), text_bytes),
+            ("read", Some("fresh"), text_bytes),
+            ("sum", None, pair_bytes),
+        ]);
+
+        for stage in stages {
+            let min = stage.get("min").unwrap();
+            assert_eq!(min.get("bytes").and_then(Value::as_f64), stage.get("bytes").and_then(Value::as_f64));
+            assert!(stage.get("tests").and_then(Value::as_f64).unwrap() >= 1.0);
+        }
+    }
+}
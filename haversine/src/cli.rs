@@ -0,0 +1,468 @@
+use std::env;
+use std::fmt;
+use std::path::Path;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use crate::bench::Stage;
+use haversine::compare::Tolerance;
+use haversine::format::Format;
+use haversine::math::MathBackend;
+use haversine::stats::HistogramScale;
+use metrics::output::OutputFormat;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Command {
+    Compute,
+    Validate,
+    Answers,
+    Bench,
+    Stats,
+    Diff,
+    ProfileDiff,
+}
+
+impl Command {
+    const ALL: [Command; 7] = [Command::Compute, Command::Validate, Command::Answers, Command::Bench, Command::Stats, Command::Diff, Command::ProfileDiff];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::Compute => "compute",
+            Command::Validate => "validate",
+            Command::Answers => "answers",
+            Command::Bench => "bench",
+            Command::Stats => "stats",
+            Command::Diff => "diff",
+            Command::ProfileDiff => "profile-diff",
+        }
+    }
+
+    /// Positional arguments after the command name.
+    fn args(self) -> &'static str {
+        match self {
+            Command::Validate => "[input] [answers.f64]",
+            Command::Diff => "[answers.f64] [answers.f64]",
+            Command::ProfileDiff => "[base.json] [new.json]",
+            _ => "[input]",
+        }
+    }
+
+    fn positional_count(self) -> usize {
+        match self {
+            Command::Validate | Command::Diff | Command::ProfileDiff => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum InputFormat {
+    Auto,
+    Pairs(Format),
+    GeoJson,
+}
+
+impl FromStr for InputFormat {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "auto" => Ok(InputFormat::Auto),
+            "geojson" => Ok(InputFormat::GeoJson),
+            _ => Format::from_name(name).map(InputFormat::Pairs).ok_or(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether bench repetitions keep their buffers or allocate new ones, so page faults are measured.
+#[derive(Copy, Clone, PartialEq)]
+pub enum AllocMode {
+    Reuse,
+    Fresh,
+}
+
+impl AllocMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            AllocMode::Reuse => "reuse",
+            AllocMode::Fresh => "fresh",
+        }
+    }
+}
+
+struct AllocModes(Vec<AllocMode>);
+
+impl FromStr for AllocModes {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "reuse" => Ok(AllocModes(vec![AllocMode::Reuse])),
+            "fresh" => Ok(AllocModes(vec![AllocMode::Fresh])),
+            "both" => Ok(AllocModes(vec![AllocMode::Reuse, AllocMode::Fresh])),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Stages(Vec<Stage>);
+
+impl FromStr for Stages {
+    type Err = ();
+
+    fn from_str(names: &str) -> Result<Self, ()> {
+        if names == "all" {
+            return Ok(Stages(Stage::ALL.to_vec()));
+        }
+        names.split(',').map(|name| Stage::from_name(name).ok_or(())).collect::<Result<_, _>>().map(Stages)
+    }
+}
+
+struct Scale(HistogramScale);
+
+impl FromStr for Scale {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        HistogramScale::from_name(name).map(Scale).ok_or(())
+    }
+}
+
+struct ProfileFormat(OutputFormat);
+
+impl FromStr for ProfileFormat {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        OutputFormat::from_name(name).map(ProfileFormat).ok_or(())
+    }
+}
+
+struct Backend(MathBackend);
+
+impl FromStr for Backend {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        MathBackend::from_name(name).map(Backend).ok_or(())
+    }
+}
+
+pub struct Options {
+    pub command: Command,
+    pub input_path: String,
+    pub answer_path: Option<String>,
+    pub input_format: InputFormat,
+    pub threads: usize,
+    pub backend: MathBackend,
+    pub report: ReportFormat,
+    pub tolerance: Tolerance,
+    pub worst_count: usize,
+    pub out_path: Option<String>,
+    pub seconds: u32,
+    pub alloc_modes: Vec<AllocMode>,
+    pub stages: Vec<Stage>,
+    pub histogram_scale: HistogramScale,
+    pub bucket_count: usize,
+    pub top_count: usize,
+    pub trace_path: Option<String>,
+    pub trace_capacity: usize,
+    pub folded_path: Option<String>,
+    pub flamegraph_path: Option<String>,
+    pub page_faults: bool,
+    pub alloc_counts: bool,
+    pub perf_counters: bool,
+    pub durations: bool,
+    pub sample_hz: Option<u32>,
+    pub save_profile_path: Option<String>,
+    pub regression_threshold: f64, // Percent
+    pub profile_out_path: Option<String>,
+    pub profile_format: OutputFormat,
+}
+
+pub const DEFAULT_WORST_COUNT: usize = 10;
+pub const DEFAULT_BENCH_SECONDS: u32 = 10;
+pub const DEFAULT_BUCKET_COUNT: usize = 20;
+pub const DEFAULT_TOP_COUNT: usize = 10;
+pub const DEFAULT_TRACE_CAPACITY: usize = 1 << 16;
+pub const SAMPLE_CAPACITY: usize = 1 << 16;
+pub const DEFAULT_REGRESSION_THRESHOLD: f64 = 5.0;
+
+pub enum Error {
+    Usage(String),
+    Io(String, std::io::Error),
+    Input(String),
+    ValidationFailed,
+}
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Error::ValidationFailed => 1,
+            Error::Usage(_) => 2,
+            Error::Io(..) => 3,
+            Error::Input(_) => 4,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{message}"),
+            Error::Io(path, e) => write!(f, "{path}: {e}"),
+            Error::Input(message) => write!(f, "{message}"),
+            Error::ValidationFailed => write!(f, "Validation failed"),
+        }
+    }
+}
+
+pub fn print_usage() {
+    let exe_name = Path::new(&env::current_exe().unwrap())
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap()
+        .to_string();
+
+    eprintln!("Usage: {exe_name} [command] [options]");
+    eprintln!("       {exe_name} [input] [answers.f64]    (compute, or validate when answers are given)");
+    eprintln!();
+    eprintln!("Commands:");
+    for command in Command::ALL {
+        eprintln!("  {:<12} {}", command.name(), command.args());
+    }
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --format [auto/json/csv/ndjson/geojson]  Input format (default auto)");
+    eprintln!("  --threads [n]                            Threads used to sum distances (default 1)");
+    eprintln!("  --math [reference/fast/precise]          Distance implementation (default reference)");
+    eprintln!("  --report [text/json]                     Output format (default text)");
+    eprintln!("  --abs [km]                               Absolute tolerance per pair (default {:e})", Tolerance::default().abs);
+    eprintln!("  --ulps [n]                               ULP tolerance per pair (default {})", Tolerance::default().ulps);
+    eprintln!("  --worst [n]                              Number of worst pairs to list (default {DEFAULT_WORST_COUNT})");
+    eprintln!("  --out [path]                             Answer file to write (default [input]_answer.f64)");
+    eprintln!("  --seconds [n]                            Bench time without a new minimum (default {DEFAULT_BENCH_SECONDS})");
+    eprintln!("  --stage [all/read,parse,sum,pipeline]    Bench stages to run (default all)");
+    eprintln!("  --alloc [reuse/fresh/both]               Reuse bench buffers or allocate per run (default reuse)");
+    eprintln!("  --histogram [linear/log]                 Stats histogram buckets (default linear)");
+    eprintln!("  --buckets [n]                            Stats histogram bucket count (default {DEFAULT_BUCKET_COUNT})");
+    eprintln!("  --top [n]                                Longest and shortest pairs to list (default {DEFAULT_TOP_COUNT})");
+    eprintln!("  --trace [path]                           Write profile blocks as a Chrome trace (needs the profile feature)");
+    eprintln!("  --trace-capacity [n]                     Trace events kept per thread (default {DEFAULT_TRACE_CAPACITY})");
+    eprintln!("  --folded [path]                          Write profile call paths as folded stacks");
+    eprintln!("  --flamegraph [path]                      Write profile call paths as an SVG flamegraph");
+    eprintln!("  --page-faults                            Count page faults in profile blocks");
+    eprintln!("  --alloc-counts                           Count allocations in profile blocks");
+    eprintln!("  --perf                                   Read performance counters in profile blocks (Linux)");
+    eprintln!("  --durations                              Report percentiles of profile block durations");
+    eprintln!("  --sample [hz]                            Sample the active profile block with SIGPROF (Linux)");
+    eprintln!("  --profile-out [path]                     Write profile records to a file");
+    eprintln!("  --profile-format [human/csv/json]        Format of --profile-out (default human)");
+    eprintln!("  --save-profile [path]                    Save profile records for profile-diff");
+    eprintln!("  --threshold [percent]                    Slowdown profile-diff fails on (default {DEFAULT_REGRESSION_THRESHOLD})");
+    eprintln!();
+    eprintln!("Exit codes: 0 success, 1 validation failed or profile regressed, 2 usage, 3 I/O error, 4 malformed input");
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, Error> {
+    let value = value.ok_or_else(|| Error::Usage(format!("{flag} needs a value")))?;
+    value.parse().map_err(|_| Error::Usage(format!("Invalid value for {flag}: {value}")))
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut positional = Vec::new();
+    let mut options = Options {
+        command: Command::Compute,
+        input_path: String::new(),
+        answer_path: None,
+        input_format: InputFormat::Auto,
+        threads: 1,
+        backend: MathBackend::Reference,
+        report: ReportFormat::Text,
+        tolerance: Tolerance::default(),
+        worst_count: DEFAULT_WORST_COUNT,
+        out_path: None,
+        seconds: DEFAULT_BENCH_SECONDS,
+        alloc_modes: vec![AllocMode::Reuse],
+        stages: Stage::ALL.to_vec(),
+        histogram_scale: HistogramScale::Linear,
+        bucket_count: DEFAULT_BUCKET_COUNT,
+        top_count: DEFAULT_TOP_COUNT,
+        trace_path: None,
+        trace_capacity: DEFAULT_TRACE_CAPACITY,
+        folded_path: None,
+        flamegraph_path: None,
+        page_faults: false,
+        alloc_counts: false,
+        perf_counters: false,
+        durations: false,
+        sample_hz: None,
+        save_profile_path: None,
+        regression_threshold: DEFAULT_REGRESSION_THRESHOLD,
+        profile_out_path: None,
+        profile_format: OutputFormat::Human,
+    };
+
+    let mut arg_iter = args.iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--format" => options.input_format = parse_value(arg, arg_iter.next())?,
+            "--threads" => options.threads = parse_value(arg, arg_iter.next())?,
+            "--math" => options.backend = parse_value::<Backend>(arg, arg_iter.next())?.0,
+            "--report" => options.report = parse_value(arg, arg_iter.next())?,
+            "--abs" => options.tolerance.abs = parse_value(arg, arg_iter.next())?,
+            "--ulps" => options.tolerance.ulps = parse_value(arg, arg_iter.next())?,
+            "--worst" => options.worst_count = parse_value(arg, arg_iter.next())?,
+            "--out" => options.out_path = Some(parse_value(arg, arg_iter.next())?),
+            "--seconds" => options.seconds = parse_value(arg, arg_iter.next())?,
+            "--alloc" => options.alloc_modes = parse_value::<AllocModes>(arg, arg_iter.next())?.0,
+            "--stage" => options.stages = parse_value::<Stages>(arg, arg_iter.next())?.0,
+            "--histogram" => options.histogram_scale = parse_value::<Scale>(arg, arg_iter.next())?.0,
+            "--buckets" => options.bucket_count = parse_value(arg, arg_iter.next())?,
+            "--top" => options.top_count = parse_value(arg, arg_iter.next())?,
+            "--trace" => options.trace_path = Some(parse_value(arg, arg_iter.next())?),
+            "--trace-capacity" => options.trace_capacity = parse_value(arg, arg_iter.next())?,
+            "--folded" => options.folded_path = Some(parse_value(arg, arg_iter.next())?),
+            "--flamegraph" => options.flamegraph_path = Some(parse_value(arg, arg_iter.next())?),
+            "--page-faults" => options.page_faults = true,
+            "--alloc-counts" => options.alloc_counts = true,
+            "--perf" => options.perf_counters = true,
+            "--durations" => options.durations = true,
+            "--sample" => options.sample_hz = Some(parse_value(arg, arg_iter.next())?),
+            "--profile-out" => options.profile_out_path = Some(parse_value(arg, arg_iter.next())?),
+            "--profile-format" => options.profile_format = parse_value::<ProfileFormat>(arg, arg_iter.next())?.0,
+            "--save-profile" => options.save_profile_path = Some(parse_value(arg, arg_iter.next())?),
+            "--threshold" => options.regression_threshold = parse_value(arg, arg_iter.next())?,
+            _ if arg.starts_with("--") => return Err(Error::Usage(format!("Unknown option {arg}"))),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if options.threads == 0 {
+        return Err(Error::Usage("--threads must be at least 1".to_string()));
+    }
+    if options.seconds == 0 {
+        // The wave would end before its first test
+        return Err(Error::Usage("--seconds must be at least 1".to_string()));
+    }
+    if options.bucket_count == 0 {
+        return Err(Error::Usage("--buckets must be at least 1".to_string()));
+    }
+    if options.trace_capacity == 0 {
+        return Err(Error::Usage("--trace-capacity must be at least 1".to_string()));
+    }
+    if options.sample_hz == Some(0) {
+        return Err(Error::Usage("--sample must be at least 1".to_string()));
+    }
+    if options.regression_threshold.is_nan() || options.regression_threshold < 0.0 {
+        return Err(Error::Usage("--threshold must be a percentage of at least 0".to_string()));
+    }
+
+    // Without a command name, keep the original [input] [answers] behaviour
+    let command = positional.first().and_then(|name| Command::ALL.into_iter().find(|c| c.name() == name));
+    options.command = match command {
+        Some(command) => {
+            positional.remove(0);
+            command
+        }
+        None if positional.len() == 2 => Command::Validate,
+        None => Command::Compute,
+    };
+
+    if positional.len() != options.command.positional_count() {
+        return Err(Error::Usage(format!("Expected {} {}", options.command.name(), options.command.args())));
+    }
+
+    let mut positional = positional.into_iter();
+    options.input_path = positional.next().unwrap();
+    options.answer_path = positional.next();
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, Error> {
+        parse_args(&args.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn commands_take_their_positional_arguments() {
+        for (args, command, input, answers) in [
+            ("in.json", Command::Compute, "in.json", None),
+            ("compute in.json", Command::Compute, "in.json", None),
+            ("in.json out.f64", Command::Validate, "in.json", Some("out.f64")),
+            ("validate in.json out.f64", Command::Validate, "in.json", Some("out.f64")),
+            ("answers in.json", Command::Answers, "in.json", None),
+            ("bench in.json", Command::Bench, "in.json", None),
+            ("stats in.json", Command::Stats, "in.json", None),
+            ("diff a.f64 b.f64", Command::Diff, "a.f64", Some("b.f64")),
+            ("profile-diff a.json b.json", Command::ProfileDiff, "a.json", Some("b.json")),
+        ] {
+            let options = parse(args).unwrap_or_else(|e| panic!("{args}: {e}"));
+            assert!(options.command == command, "{args}");
+            assert_eq!(options.input_path, input, "{args}");
+            assert_eq!(options.answer_path.as_deref(), answers, "{args}");
+        }
+    }
+
+    #[test]
+    fn options_set_their_values() {
+        let args = "bench in.csv --format csv --threads 4 --math precise --seconds 2 --alloc both --buckets 8 --threshold 2.5 --page-faults";
+        let options = parse(args).unwrap_or_else(|e| panic!("{args}: {e}"));
+        assert!(options.input_format == InputFormat::Pairs(Format::Csv));
+        assert_eq!(options.threads, 4);
+        assert!(options.backend == MathBackend::Precise);
+        assert_eq!(options.seconds, 2);
+        assert_eq!(options.alloc_modes.len(), 2);
+        assert_eq!(options.bucket_count, 8);
+        assert_eq!(options.regression_threshold, 2.5);
+        assert!(options.page_faults && !options.alloc_counts);
+    }
+
+    #[test]
+    fn bad_arguments_are_usage_errors() {
+        for args in [
+            "",
+            "validate in.json",
+            "compute in.json extra",
+            "a b c",
+            "in.json --unknown",
+            "in.json --threads",
+            "in.json --threads many",
+            "in.json --threads 0",
+            "in.json --buckets 0",
+            "bench in.json --seconds 0",
+            "in.json --threshold -1",
+            "in.json --threshold NaN",
+            "in.json --format xml",
+            "in.json --math slow",
+        ] {
+            match parse(args) {
+                Err(e @ Error::Usage(_)) => assert!(e.exit_code() == ExitCode::from(2), "{args}"),
+                Err(e) => panic!("{args}: not a usage error: {e}"),
+                Ok(_) => panic!("{args}: accepted"),
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+/// Number of representable f64s between a and b, u64::MAX if either is NaN.
+pub fn ulp_distance(a: f64, b: f64) -> u64 {
+    if a.is_nan() || b.is_nan() {
+        return u64::MAX;
+    }
+
+    // Map the sign-magnitude bits onto a monotonic integer line, so -0.0 and 0.0 are both 0
+    fn ordered(x: f64) -> i64 {
+        let bits = x.to_bits() as i64;
+        if bits < 0 { i64::MIN - bits } else { bits }
+    }
+    ordered(a).abs_diff(ordered(b))
+}
+
+/// Values match if they are within either the absolute or the ULP tolerance.
+#[derive(Copy, Clone, Debug)]
+pub struct Tolerance {
+    pub abs: f64,
+    pub ulps: u64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance { abs: 1e-9, ulps: 4 }
+    }
+}
+
+impl Tolerance {
+    pub fn matches(&self, value: f64, reference: f64) -> bool {
+        (value - reference).abs() <= self.abs || ulp_distance(value, reference) <= self.ulps
+    }
+}
+
+/// Keeps the `capacity` entries with the largest keys seen so far, largest first.
+pub struct WorstList<T> {
+    capacity: usize,
+    entries: Vec<(f64, T)>,
+}
+
+impl<T> WorstList<T> {
+    pub fn new(capacity: usize) -> Self {
+        WorstList { capacity, entries: Vec::with_capacity(capacity + 1) }
+    }
+
+    pub fn push(&mut self, key: f64, value: T) {
+        if self.entries.len() == self.capacity && self.entries.last().is_none_or(|(worst, _)| key <= *worst) {
+            return;
+        }
+
+        let idx = self.entries.partition_point(|(existing, _)| *existing >= key);
+        self.entries.insert(idx, (key, value));
+        self.entries.truncate(self.capacity);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(f64, T)> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulps() {
+        assert_eq!(ulp_distance(1.0, 1.0), 0);
+        assert_eq!(ulp_distance(1.0, f64::from_bits(1.0f64.to_bits() + 3)), 3);
+        assert_eq!(ulp_distance(0.0, -0.0), 0);
+        assert_eq!(ulp_distance(f64::from_bits(1), -f64::from_bits(1)), 2);
+        assert_eq!(ulp_distance(f64::NAN, 1.0), u64::MAX);
+    }
+
+    #[test]
+    fn worst_list_keeps_largest() {
+        let mut worst = WorstList::new(3);
+        for (i, key) in [5.0, 1.0, 7.0, 3.0, 9.0, 2.0].into_iter().enumerate() {
+            worst.push(key, i);
+        }
+        assert_eq!(worst.iter().map(|(_, i)| *i).collect::<Vec<_>>(), [4, 2, 0]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::f64::consts::PI;
+use std::mem::size_of_val;
+
+use haversine::compare::WorstList;
+use haversine::json::{object, Value};
+use haversine::stats::{Histogram, HistogramScale, QuantileSketch, RunningStats};
+use haversine::{time_bandwidth, time_block, Pair};
+
+use crate::cli::{Error, Options, ReportFormat};
+use crate::{input_report, Input, Outcome, EARTH_RADIUS};
+
+const PERCENTILES: [f64; 9] = [1.0, 5.0, 10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0];
+const SKETCH_ALPHA: f64 = 0.001;
+/// Smallest distance given its own log bucket, one metre.
+const LOG_HISTOGRAM_MIN: f64 = 0.001;
+const HISTOGRAM_BAR_WIDTH: u64 = 40;
+
+struct DistanceStats {
+    stats: RunningStats,
+    sketch: QuantileSketch,
+    histogram: Histogram,
+    longest: WorstList<(usize, Pair)>,
+    shortest: WorstList<(usize, Pair)>,
+}
+
+/// One pass over the pairs without storing the distances.
+fn collect_stats(options: &Options, pairs: &[Pair]) -> DistanceStats {
+    time_bandwidth!("DistanceStats", size_of_val(pairs));
+
+    // No two points are further apart than half the circumference, so the range is known up front
+    let max_distance = PI * EARTH_RADIUS;
+    let lo = match options.histogram_scale {
+        HistogramScale::Linear => 0.0,
+        HistogramScale::Log => LOG_HISTOGRAM_MIN,
+    };
+
+    let mut stats = DistanceStats {
+        stats: RunningStats::default(),
+        sketch: QuantileSketch::new(SKETCH_ALPHA),
+        histogram: Histogram::new(options.histogram_scale, lo, max_distance, options.bucket_count),
+        longest: WorstList::new(options.top_count),
+        shortest: WorstList::new(options.top_count),
+    };
+
+    for (index, pair) in pairs.iter().enumerate() {
+        let distance = options.backend.distance(pair, EARTH_RADIUS);
+        stats.stats.push(distance);
+        stats.sketch.push(distance);
+        stats.histogram.push(distance);
+        stats.longest.push(distance, (index, *pair));
+        stats.shortest.push(-distance, (index, *pair));
+    }
+
+    stats
+}
+
+fn print_pairs<'a>(title: &str, pairs: impl Iterator<Item = (f64, &'a (usize, Pair))>) {
+    println!("{title}:");
+    for (distance, (index, Pair { x0, y0, x1, y1 })) in pairs {
+        println!("  [{index}] ({x0:.16}, {y0:.16}) -> ({x1:.16}, {y1:.16}): {distance:.16}");
+    }
+}
+
+fn print_stats(stats: &DistanceStats) {
+    let DistanceStats { stats: running, sketch, histogram, longest, shortest } = stats;
+
+    println!("Pair count: {}", running.count);
+    println!("Min: {:.16}", running.min);
+    println!("Max: {:.16}", running.max);
+    println!("Mean: {:.16}", running.mean());
+    println!("Std dev: {:.16}", running.std_dev());
+
+    println!();
+    println!("Percentiles (within {}%):", sketch.alpha() * 100.0);
+    for percentile in PERCENTILES {
+        if let Some(value) = sketch.quantile(percentile / 100.0) {
+            println!("  p{percentile}: {value:.4}");
+        }
+    }
+
+    println!();
+    println!("Histogram ({}, {} buckets):", histogram.scale.name(), histogram.counts.len());
+    let max_count = histogram.counts.iter().copied().max().unwrap_or(0).max(1);
+    for (i, &count) in histogram.counts.iter().enumerate() {
+        let bar = "#".repeat((count * HISTOGRAM_BAR_WIDTH).div_ceil(max_count) as usize);
+        println!("  [{:>12.4}, {:>12.4}): {count:>10} {bar}", histogram.edge(i), histogram.edge(i + 1));
+    }
+
+    println!();
+    print_pairs("Longest pairs", longest.iter().map(|(distance, pair)| (*distance, pair)));
+    print_pairs("Shortest pairs", shortest.iter().map(|(distance, pair)| (-*distance, pair)));
+}
+
+fn pairs_json<'a>(pairs: impl Iterator<Item = (f64, &'a (usize, Pair))>) -> Value {
+    let pairs = pairs.map(|(distance, &(index, Pair { x0, y0, x1, y1 }))| object([
+        ("index", index.into()),
+        ("x0", x0.into()),
+        ("y0", y0.into()),
+        ("x1", x1.into()),
+        ("y1", y1.into()),
+        ("distance", distance.into()),
+    ]));
+    Value::Array(pairs.collect())
+}
+
+fn stats_json(stats: &DistanceStats) -> Vec<(String, Value)> {
+    let DistanceStats { stats: running, sketch, histogram, longest, shortest } = stats;
+
+    let percentiles = PERCENTILES.iter().map(|&percentile| object([
+        ("percentile", percentile.into()),
+        ("value", sketch.quantile(percentile / 100.0).into()),
+    ]));
+    let buckets = histogram.counts.iter().enumerate().map(|(i, &count)| object([
+        ("lo", histogram.edge(i).into()),
+        ("hi", histogram.edge(i + 1).into()),
+        ("count", count.into()),
+    ]));
+
+    let members = [
+        ("min", running.min.into()),
+        ("max", running.max.into()),
+        ("mean", running.mean().into()),
+        ("std_dev", running.std_dev().into()),
+        ("percentile_error", sketch.alpha().into()),
+        ("percentiles", Value::Array(percentiles.collect())),
+        ("histogram", object([
+            ("scale", histogram.scale.name().into()),
+            ("buckets", Value::Array(buckets.collect())),
+        ])),
+        ("longest", pairs_json(longest.iter().map(|(distance, pair)| (*distance, pair)))),
+        ("shortest", pairs_json(shortest.iter().map(|(distance, pair)| (-*distance, pair)))),
+    ];
+    members.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+pub fn stats(options: &Options, input: &Input) -> Result<Outcome, Error> {
+    let stats = collect_stats(options, &input.pairs);
+
+    time_block!("MiscOutput");
+
+    if options.report == ReportFormat::Text {
+        print_stats(&stats);
+    }
+
+    let mut report = input_report(options, input);
+    report.extend(stats_json(&stats));
+    Ok(Outcome { report: Value::Object(report), passed: true })
+}
@@ -0,0 +1,207 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::profile::ThreadTimeRecords;
+
+/// One line of folded stack output: frames from the root separated by `;`, and the self time of
+/// the innermost frame. Tools add up the lines below a frame to get its inclusive time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FoldedStack {
+    pub stack: String,
+    pub ticks: u64,
+}
+
+// Frame names can't contain the separators of the folded format
+fn frame_name(label: &str) -> String {
+    label.replace(';', ":").replace(char::is_whitespace, "_")
+}
+
+/// Call paths of every thread as folded stacks, rooted at the thread name, in tree order.
+pub fn folded_stacks(threads: &[ThreadTimeRecords]) -> Vec<FoldedStack> {
+    let mut stacks = Vec::new();
+    for thread in threads {
+        let mut paths = Vec::<String>::with_capacity(thread.call_tree.len());
+        for node in &thread.call_tree {
+            let parent = node.parent.map_or_else(|| frame_name(&thread.thread), |parent| paths[parent].clone());
+            let path = format!("{parent};{}", frame_name(node.record.label));
+
+            // Exclusive time can come out negative from timer noise, which tools can't take
+            let ticks = node.record.elapsed_exclusive as i64;
+            if ticks > 0 {
+                stacks.push(FoldedStack { stack: path.clone(), ticks: ticks as u64 });
+            }
+            paths.push(path);
+        }
+    }
+    stacks
+}
+
+pub fn write_folded_stacks(out: &mut impl Write, stacks: &[FoldedStack]) -> io::Result<()> {
+    for FoldedStack { stack, ticks } in stacks {
+        writeln!(out, "{stack} {ticks}")?;
+    }
+    Ok(())
+}
+
+const SVG_WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const FONT_SIZE: f64 = 12.0;
+const CHAR_WIDTH: f64 = FONT_SIZE * 0.59;
+const TITLE_HEIGHT: f64 = 32.0;
+const MARGIN: f64 = 10.0;
+/// Frames narrower than this are left out, along with everything above them.
+const MIN_FRAME_WIDTH: f64 = 0.1;
+
+#[derive(Default)]
+struct Frame {
+    name: String,
+    ticks: u64,
+    children: Vec<Frame>,
+}
+
+impl Frame {
+    fn insert(&mut self, frames: &[&str], ticks: u64) {
+        self.ticks += ticks;
+        if let Some((&name, rest)) = frames.split_first() {
+            let index = match self.children.iter().position(|child| child.name == name) {
+                Some(index) => index,
+                None => {
+                    self.children.push(Frame { name: name.to_string(), ..Frame::default() });
+                    self.children.len() - 1
+                }
+            };
+            self.children[index].insert(rest, ticks);
+        }
+    }
+
+    fn depth(&self) -> usize {
+        1 + self.children.iter().map(Frame::depth).max().unwrap_or(0)
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Warm colour that stays the same for a name across renders.
+fn frame_color(name: &str) -> (u8, u8, u8) {
+    // FNV-1a
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    (205 + (hash % 50) as u8, 80 + ((hash >> 8) % 150) as u8, ((hash >> 16) % 55) as u8)
+}
+
+struct Layout {
+    total: u64,
+    ticks_per_pixel: f64,
+    bottom: f64,
+}
+
+fn render_frame(svg: &mut String, frame: &Frame, layout: &Layout, x: f64, depth: usize) {
+    let width = frame.ticks as f64 / layout.ticks_per_pixel;
+    if width < MIN_FRAME_WIDTH {
+        return;
+    }
+
+    let y = layout.bottom - (depth + 1) as f64 * FRAME_HEIGHT;
+    let (r, g, b) = frame_color(&frame.name);
+    let name = escape_xml(&frame.name);
+    let percent = frame.ticks as f64 * 100.0 / layout.total as f64;
+
+    let _ = writeln!(svg, r#"<g><title>{name} ({} ticks, {percent:.2}%)</title>"#, frame.ticks);
+    let _ = writeln!(svg, r#"<rect x="{x:.1}" y="{y:.1}" width="{width:.1}" height="{:.1}" fill="rgb({r},{g},{b})" rx="2"/>"#, FRAME_HEIGHT - 1.0);
+
+    // Only as much of the name as fits, and nothing when not even a couple of characters do
+    let fits = ((width - 6.0) / CHAR_WIDTH) as usize;
+    if fits >= 3 {
+        let chars = frame.name.chars().count();
+        let text = if chars <= fits { frame.name.clone() } else { frame.name.chars().take(fits - 2).collect::<String>() + ".." };
+        let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}">{}</text>"#, x + 3.0, y + FRAME_HEIGHT - 4.0, escape_xml(&text));
+    }
+    svg.push_str("</g>\n");
+
+    let mut child_x = x;
+    for child in &frame.children {
+        render_frame(svg, child, layout, child_x, depth + 1);
+        child_x += child.ticks as f64 / layout.ticks_per_pixel;
+    }
+}
+
+/// Self-contained SVG flamegraph of `stacks`, with the root at the bottom and a tooltip per frame.
+pub fn render_flamegraph(stacks: &[FoldedStack], title: &str) -> String {
+    let mut root = Frame { name: "all".to_string(), ..Frame::default() };
+    for FoldedStack { stack, ticks } in stacks {
+        root.insert(&stack.split(';').collect::<Vec<_>>(), *ticks);
+    }
+
+    let height = TITLE_HEIGHT + root.depth() as f64 * FRAME_HEIGHT + MARGIN;
+    let layout = Layout {
+        total: root.ticks.max(1),
+        ticks_per_pixel: root.ticks.max(1) as f64 / (SVG_WIDTH - 2.0 * MARGIN),
+        bottom: height - MARGIN,
+    };
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<?xml version="1.0" standalone="no"?>"#);
+    let _ = writeln!(svg, r#"<svg version="1.1" width="{SVG_WIDTH}" height="{height}" viewBox="0 0 {SVG_WIDTH} {height}" xmlns="http://www.w3.org/2000/svg">"#);
+    let _ = writeln!(svg, r#"<style>text {{ font-family: Verdana, sans-serif; font-size: {FONT_SIZE}px; fill: #000; pointer-events: none; }} rect:hover {{ stroke: #000; stroke-width: 0.5; }}</style>"#);
+    let _ = writeln!(svg, r##"<rect width="100%" height="100%" fill="#f8f8f0"/>"##);
+    let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle" style="font-size: {:.0}px">{}</text>"#, SVG_WIDTH / 2.0, TITLE_HEIGHT - 10.0, FONT_SIZE * 1.4, escape_xml(title));
+    render_frame(&mut svg, &root, &layout, MARGIN, 0);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{CallNode, TimeRecord};
+
+    fn node(parent: Option<usize>, depth: usize, label: &'static str, exclusive: u64) -> CallNode {
+        let record = TimeRecord {
+            label,
+            elapsed_exclusive: exclusive,
+            elapsed_inclusive: 0,
+            byte_count: 0,
+            hit_count: 1,
+            page_faults_exclusive: 0,
+            page_faults_inclusive: 0,
+            alloc_count: 0,
+            alloc_bytes: 0,
+            perf: Default::default(),
+            durations: None,
+            samples: 0,
+        };
+        CallNode { parent, depth, anchor: 0, record }
+    }
+
+    #[test]
+    fn folds_call_paths() {
+        let call_tree = vec![
+            node(None, 0, "parse pairs", 10),
+            node(Some(0), 1, "parse;num", 5),
+            node(None, 0, "sum", 0),
+            node(Some(2), 1, "distance", 7),
+        ];
+        let threads = [ThreadTimeRecords { thread: "main".to_string(), records: Vec::new(), call_tree }];
+
+        let mut out = Vec::new();
+        write_folded_stacks(&mut out, &folded_stacks(&threads)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "main;parse_pairs 10\nmain;parse_pairs;parse:num 5\nmain;sum;distance 7\n");
+    }
+
+    #[test]
+    fn renders_frames() {
+        let stacks = [
+            FoldedStack { stack: "main;a<b>".to_string(), ticks: 3 },
+            FoldedStack { stack: "main;c".to_string(), ticks: 1 },
+            FoldedStack { stack: "main;a<b>;d".to_string(), ticks: 1 },
+        ];
+        let svg = render_flamegraph(&stacks, "Profile");
+
+        // all, main, a<b>, c, d
+        assert_eq!(svg.matches("<g>").count(), 5);
+        assert!(svg.contains("<title>a&lt;b&gt; (4 ticks, 80.00%)</title>"));
+        assert!(svg.contains("<title>all (5 ticks, 100.00%)</title>"));
+        assert!(!svg.contains("a<b>"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::Pair;
+
+/// Text encodings for coordinate pairs understood by the processor and written by the generator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `{"pairs": [{"x0":..., "y0":..., "x1":..., "y1":...}, ...]}`
+    Json,
+    /// `x0,y0,x1,y1` header followed by one pair per line.
+    Csv,
+    /// One `{"x0":..., "y0":..., "x1":..., "y1":...}` object per line.
+    NdJson,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Json, Format::Csv, Format::NdJson];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::NdJson => "ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.name()
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "ndjson" | "jsonl" => Some(Format::NdJson),
+            _ => None,
+        }
+    }
+
+    pub fn from_extension(path: &Path) -> Option<Format> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(Format::from_name)
+    }
+
+    /// Guess the format from the start of the input text.
+    pub fn sniff(input: &str) -> Format {
+        let input = input.trim_start();
+        if !input.starts_with('{') {
+            return Format::Csv;
+        }
+
+        // A complete pair object on the first line means one object per line. Pairs hold no arrays,
+        // so a line with one is a whole JSON document on one line.
+        let first_line = input.lines().next().unwrap_or("").trim_end().trim_end_matches(',');
+        if first_line.ends_with('}') && first_line.contains("\"x0\"") && !first_line.contains('[') {
+            Format::NdJson
+        } else {
+            Format::Json
+        }
+    }
+
+    /// Use the file extension if it is a known one, otherwise look at the content.
+    pub fn detect(path: &Path, input: &str) -> Format {
+        Format::from_extension(path).unwrap_or_else(|| Format::sniff(input))
+    }
+
+    pub fn write_begin(self, out: &mut String) {
+        match self {
+            Format::Json => *out += "{\"pairs\": [\n",
+            Format::Csv => *out += "x0,y0,x1,y1\n",
+            Format::NdJson => {}
+        }
+    }
+
+    pub fn write_pair(self, out: &mut String, pair: &Pair, last: bool) {
+        let Pair { x0, y0, x1, y1 } = pair;
+        // Writing to a String can't fail
+        let _ = match self {
+            Format::Json => {
+                let json_sep = if last { "\n" } else { ",\n" };
+                write!(out, "    {{\"x0\":{x0:.16}, \"y0\":{y0:.16}, \"x1\":{x1:.16}, \"y1\":{y1:.16}}}{json_sep}")
+            }
+            Format::Csv => writeln!(out, "{x0:.16},{y0:.16},{x1:.16},{y1:.16}"),
+            Format::NdJson => writeln!(out, "{{\"x0\":{x0:.16}, \"y0\":{y0:.16}, \"x1\":{x1:.16}, \"y1\":{y1:.16}}}"),
+        };
+    }
+
+    pub fn write_end(self, out: &mut String) {
+        match self {
+            Format::Json => *out += "]}\n",
+            Format::Csv | Format::NdJson => {}
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::ops::Range;
+use std::path::Path;
+
+use haversine::format::Format;
+use haversine::json::{self, Value};
+use haversine::time_function;
+use haversine::Pair;
+
+pub struct Feature {
+    pub name: String,
+    /// Range of this feature's segments in `GeoJson::pairs`
+    pub pairs: Range<usize>,
+}
+
+pub struct GeoJson {
+    pub pairs: Vec<Pair>,
+    pub features: Vec<Feature>,
+}
+
+/// `.geojson` files, or unknown extensions whose content has a top-level `type` member instead of pairs.
+pub fn is_geojson(path: &Path, input: &str) -> bool {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    if extension.eq_ignore_ascii_case("geojson") {
+        return true;
+    }
+    // GeoJSON is often saved as plain .json, so only the other known extensions rule it out
+    if !matches!(Format::from_extension(path), None | Some(Format::Json)) || !input.trim_start().starts_with('{') {
+        return false;
+    }
+
+    // Only look at the start so big pair files aren't scanned twice
+    let head = &input.as_bytes()[..input.len().min(64 * 1024)];
+    let find = |key: &[u8]| head.windows(key.len()).position(|window| window == key);
+    match (find(b"\"type\""), find(b"\"x0\"")) {
+        (Some(type_pos), Some(x0_pos)) => type_pos < x0_pos,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+fn position(value: &Value) -> Result<(f64, f64), String> {
+    // GeoJSON positions are [longitude, latitude, (altitude)]
+    match value.as_array() {
+        Some([lon, lat, ..]) => match (lon.as_f64(), lat.as_f64()) {
+            (Some(lon), Some(lat)) => Ok((lon, lat)),
+            _ => Err("Position coordinates must be numbers".to_string()),
+        },
+        _ => Err("Position must be an array of at least two numbers".to_string()),
+    }
+}
+
+fn push_line_string(pairs: &mut Vec<Pair>, coordinates: &Value) -> Result<(), String> {
+    let positions = coordinates.as_array().ok_or("LineString coordinates must be an array")?;
+
+    let mut previous = None;
+    for position in positions.iter().map(position) {
+        let (x1, y1) = position?;
+        if let Some((x0, y0)) = previous {
+            pairs.push(Pair { x0, y0, x1, y1 });
+        }
+        previous = Some((x1, y1));
+    }
+
+    Ok(())
+}
+
+/// Returns false if the geometry has no line segments.
+fn push_geometry(pairs: &mut Vec<Pair>, geometry: &Value) -> Result<bool, String> {
+    let coordinates = geometry.get("coordinates");
+    match geometry.get("type").and_then(Value::as_str) {
+        Some("LineString") => {
+            push_line_string(pairs, coordinates.ok_or("LineString without coordinates")?)?;
+        }
+        Some("MultiLineString") => {
+            let lines = coordinates.and_then(Value::as_array).ok_or("MultiLineString coordinates must be an array")?;
+            for line in lines {
+                push_line_string(pairs, line)?;
+            }
+        }
+        Some("GeometryCollection") => {
+            let geometries = geometry.get("geometries").and_then(Value::as_array).ok_or("GeometryCollection without geometries")?;
+            let mut has_lines = false;
+            for geometry in geometries {
+                has_lines |= push_geometry(pairs, geometry)?;
+            }
+            return Ok(has_lines);
+        }
+        Some(_) => return Ok(false),
+        None => return Err("Geometry without a type".to_string()),
+    }
+
+    Ok(true)
+}
+
+fn feature_name(feature: &Value, index: usize) -> String {
+    let name = feature.get("properties").and_then(|properties| properties.get("name"));
+    match (name, feature.get("id")) {
+        (Some(Value::String(name)), _) => name.clone(),
+        (_, Some(Value::String(id))) => id.clone(),
+        (_, Some(Value::Number(id))) => id.to_string(),
+        _ => format!("#{index}"),
+    }
+}
+
+fn push_feature(geojson: &mut GeoJson, name: String, geometry: &Value) -> Result<(), String> {
+    let start = geojson.pairs.len();
+    if push_geometry(&mut geojson.pairs, geometry)? {
+        geojson.features.push(Feature { name, pairs: start..geojson.pairs.len() });
+    }
+    Ok(())
+}
+
+pub fn parse_geojson(input: &str) -> Result<GeoJson, String> {
+    time_function!();
+
+    let document = json::parse(input).map_err(|e| e.to_string())?;
+
+    let mut geojson = GeoJson { pairs: Vec::new(), features: Vec::new() };
+
+    match document.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => {
+            let features = document.get("features").and_then(Value::as_array).ok_or("FeatureCollection without features")?;
+            for (index, feature) in features.iter().enumerate() {
+                // Features may have a null geometry
+                if let Some(geometry @ Value::Object(_)) = feature.get("geometry") {
+                    push_feature(&mut geojson, feature_name(feature, index), geometry)?;
+                }
+            }
+        }
+        Some("Feature") => {
+            if let Some(geometry @ Value::Object(_)) = document.get("geometry") {
+                push_feature(&mut geojson, feature_name(&document, 0), geometry)?;
+            }
+        }
+        Some(_) => push_feature(&mut geojson, "#0".to_string(), &document)?,
+        None => return Err("Document without a type".to_string()),
+    }
+
+    Ok(geojson)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_collection_segments() {
+        let input = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {"name": "route"},
+             "geometry": {"type": "LineString", "coordinates": [[10, 50], [11, 51, 100], [12, 52]]}},
+            {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [0, 0]}},
+            {"type": "Feature", "id": "multi", "properties": null,
+             "geometry": {"type": "MultiLineString", "coordinates": [[[0, 0], [1, 1]], [[5, 5], [6, 6]]]}}
+        ]}"#;
+
+        let geojson = parse_geojson(input).unwrap();
+        assert_eq!(geojson.pairs.len(), 4);
+        assert_eq!(geojson.pairs[0], Pair { x0: 10.0, y0: 50.0, x1: 11.0, y1: 51.0 });
+        // Lines of a MultiLineString are not joined
+        assert_eq!(geojson.pairs[3], Pair { x0: 5.0, y0: 5.0, x1: 6.0, y1: 6.0 });
+
+        assert_eq!(geojson.features.len(), 2);
+        assert_eq!(geojson.features[0].name, "route");
+        assert_eq!(geojson.features[0].pairs, 0..2);
+        assert_eq!(geojson.features[1].name, "multi");
+        assert_eq!(geojson.features[1].pairs, 2..4);
+    }
+
+    #[test]
+    fn detection() {
+        let line_string = r#"{"type": "LineString", "coordinates": [[0, 0], [1, 1]]}"#;
+        assert!(is_geojson(Path::new("route.geojson"), ""));
+        assert!(is_geojson(Path::new("route.txt"), line_string));
+        assert!(is_geojson(Path::new("route.json"), line_string));
+        assert!(!is_geojson(Path::new("route.csv"), line_string));
+        assert!(!is_geojson(Path::new("pairs.txt"), r#"{"pairs": [{"x0":1, "y0":2, "x1":3, "y1":4}]}"#));
+        assert_eq!(parse_geojson(line_string).unwrap().pairs.len(), 1);
+    }
+}
@@ -0,0 +1,423 @@
+use std::fmt;
+
+/// A parsed JSON document. Objects keep their keys in document order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(num: f64) -> Self {
+        Value::Number(num)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(num: u64) -> Self {
+        Value::Number(num as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(num: usize) -> Self {
+        Value::Number(num as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Value::String(string.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::String(string)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Build an object from `(key, value)` pairs, e.g. `object([("sum", sum.into())])`.
+pub fn object<const N: usize>(members: [(&str, Value); N]) -> Value {
+    Value::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn write_string(f: &mut fmt::Formatter, string: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl Value {
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        // {:#} pretty prints with two space indents
+        let pretty = f.alternate();
+        let newline = |f: &mut fmt::Formatter, indent: usize| -> fmt::Result {
+            if pretty { write!(f, "\n{:1$}", "", indent * 2) } else { Ok(()) }
+        };
+
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            // JSON has no representation for NaN or infinity
+            Value::Number(num) if !num.is_finite() => f.write_str("null"),
+            Value::Number(num) => write!(f, "{num}"),
+            Value::String(string) => write_string(f, string),
+            Value::Array(values) if values.is_empty() => f.write_str("[]"),
+            Value::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    newline(f, indent + 1)?;
+                    value.write(f, indent + 1)?;
+                }
+                newline(f, indent)?;
+                f.write_str("]")
+            }
+            Value::Object(members) if members.is_empty() => f.write_str("{}"),
+            Value::Object(members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    newline(f, indent + 1)?;
+                    write_string(f, key)?;
+                    f.write_str(if pretty { ": " } else { ":" })?;
+                    value.write(f, indent + 1)?;
+                }
+                newline(f, indent)?;
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+pub fn parse(input: &str) -> Result<Value, JsonError> {
+    let mut parser = Parser { input: input.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.input.len() {
+        return Err(parser.error("Trailing characters"));
+    }
+    Ok(value)
+}
+
+/// Objects and arrays nest at most this deep, so deep input fails instead of overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> JsonError {
+        JsonError { offset: self.pos, message }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error("Unexpected character"))
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &[u8], value: Value) -> Result<Value, JsonError> {
+        if self.input[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(self.error("Invalid literal"))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(open @ (b'{' | b'[')) => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error("Nested too deeply"));
+                }
+                self.depth += 1;
+                let value = if open == b'{' { self.parse_object() } else { self.parse_array() };
+                self.depth -= 1;
+                value
+            }
+            Some(b'"') => self.parse_string().map(Value::String),
+            Some(b't') => self.parse_literal(b"true", Value::Bool(true)),
+            Some(b'f') => self.parse_literal(b"false", Value::Bool(false)),
+            Some(b'n') => self.parse_literal(b"null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, JsonError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("Expected object key"));
+            }
+            let key = self.parse_string()?;
+            self.expect(b':')?;
+            members.push((key, self.parse_value()?));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("Expected , or }")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, JsonError> {
+        self.pos += 1;
+        let mut values = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.parse_value()?);
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                _ => return Err(self.error("Expected , or ]")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut string = Vec::new();
+
+        loop {
+            let Some(byte) = self.peek() else {
+                return Err(self.error("Unterminated string"));
+            };
+            self.pos += 1;
+
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.peek() {
+                        Some(b'u') => {
+                            self.pos += 1;
+                            self.parse_unicode_escape()?
+                        }
+                        Some(byte) => {
+                            self.pos += 1;
+                            match byte {
+                                b'"' => '"',
+                                b'\\' => '\\',
+                                b'/' => '/',
+                                b'b' => '\u{8}',
+                                b'f' => '\u{c}',
+                                b'n' => '\n',
+                                b'r' => '\r',
+                                b't' => '\t',
+                                _ => return Err(self.error("Invalid escape")),
+                            }
+                        }
+                        None => return Err(self.error("Unterminated string")),
+                    };
+                    let mut buf = [0; 4];
+                    string.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                }
+                _ => string.push(byte),
+            }
+        }
+
+        // The input came from a &str and escapes are pushed as UTF-8, so this can't fail
+        String::from_utf8(string).map_err(|_| self.error("Invalid UTF-8"))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let hex = self.input.get(self.pos..self.pos + 4).ok_or_else(|| self.error("Invalid unicode escape"))?;
+        let hex = std::str::from_utf8(hex).map_err(|_| self.error("Invalid unicode escape"))?;
+        let code = u32::from_str_radix(hex, 16).map_err(|_| self.error("Invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char, JsonError> {
+        let mut code = self.parse_hex4()?;
+
+        // Combine UTF-16 surrogate pairs. A lone surrogate of either half fails from_u32 below.
+        if (0xd800..0xdc00).contains(&code) && self.input[self.pos..].starts_with(b"\\u") {
+            self.pos += 2;
+            let low = self.parse_hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("Invalid surrogate pair"));
+            }
+            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+        }
+
+        char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))
+    }
+
+    fn parse_number(&mut self) -> Result<Value, JsonError> {
+        let start = self.pos;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+
+        // Only ASCII was consumed, so the slice is valid UTF-8
+        let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+        text.parse::<f64>().map(Value::Number).map_err(|_| JsonError { offset: start, message: "Invalid number" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_write() {
+        let input = r#"{"a": [1, -2.5e3, true, null], "b\n\"": "x\u00e9\ud83d\ude00", "c": {}}"#;
+        let value = parse(input).unwrap();
+        assert_eq!(value.get("a").unwrap().as_array().unwrap()[1], Value::Number(-2500.0));
+        assert_eq!(value.get("b\n\"").unwrap().as_str(), Some("x\u{e9}\u{1f600}"));
+
+        let written = value.to_string();
+        assert_eq!(written, "{\"a\":[1,-2500,true,null],\"b\\n\\\"\":\"x\u{e9}\u{1f600}\",\"c\":{}}");
+        assert_eq!(parse(&written).unwrap(), value);
+        assert_eq!(parse(&format!("{value:#}")).unwrap(), value);
+
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("1 2").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_surrogates_and_deep_nesting() {
+        assert!(parse(r#""\ud800\u0041""#).is_err());
+        assert!(parse(r#""\ud800""#).is_err());
+        assert!(parse(r#""\udc00""#).is_err());
+
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(parse(&nested(100_000)).unwrap_err().message, "Nested too deeply");
+    }
+}
@@ -1,7 +1,23 @@
-
 #[cfg_attr(feature="profile", path="profile.rs")]
 #[cfg_attr(not(feature="profile"), path="profile_stub.rs")]
 pub mod profile;
+pub mod answer;
+pub mod compare;
+pub mod flamegraph;
+pub mod format;
+pub mod json;
+pub mod math;
+pub mod profile_output;
+pub mod saved_profile;
+pub mod stats;
+
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct Pair {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
 
 // NOTE(casey): earth_radius is generally expected to be 6372.8
 pub fn reference_haversine(x0: f64, y0: f64, x1: f64, y1: f64, earth_radius: f64) -> f64
@@ -24,4 +40,13 @@ pub fn reference_haversine(x0: f64, y0: f64, x1: f64, y1: f64, earth_radius: f64
     let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
     let c = 2.0 * a.sqrt().asin();
     earth_radius * c
-}
\ No newline at end of file
+}
+
+pub fn sum_haversine_distances(pairs: &[Pair], earth_radius: f64) -> f64 {
+    math::MathBackend::Reference.sum_distances(pairs, earth_radius)
+}
+
+/// Total length of the path formed by the pairs, rather than the average distance.
+pub fn sum_path_length(pairs: &[Pair], earth_radius: f64) -> f64 {
+    pairs.iter().map(|&Pair { x0, y0, x1, y1 }| reference_haversine(x0, y0, x1, y1, earth_radius)).sum()
+}
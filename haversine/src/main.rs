@@ -1,119 +1,444 @@
+mod answer_diff;
+mod bench;
+mod cli;
+mod distribution;
+mod geojson;
 mod parser;
+mod profile_diff;
+mod validate;
 
-use std::mem::size_of;
+use std::mem::size_of_val;
 use std::path::Path;
-use std::{env, fs};
+use std::{env, fs, io};
 use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::ExitCode;
 
-use parser::parse_pairs;
-use haversine::profile::{print_time_records, time_block, time_bandwidth};
-use metrics::timing::{estimate_cpu_frequency, read_cpu_timer};
+use cli::{parse_args, print_usage, Command, Error, InputFormat, Options, ReportFormat, SAMPLE_CAPACITY};
+use geojson::{parse_geojson, Feature};
+use parser::parse_input_into;
+use validate::validate_pairs;
+use haversine::answer::AnswerFile;
+use haversine::flamegraph::{folded_stacks, render_flamegraph, write_folded_stacks};
+use haversine::format::Format;
+use haversine::json::{object, Value};
+use haversine::Pair;
+use haversine::saved_profile::SavedProfile;
+use haversine::profile::{print_time_records, calibrate_overhead, chrome_trace, start_sampling, start_trace, stop_sampling, track_durations, track_page_faults, track_perf_counters, thread_time_records, thread_traces, time_records, time_block, time_bandwidth, track_allocations};
+use haversine::profile_output::{profile_json, write_time_records};
+use metrics::perf::PerfEvent;
+use metrics::timing::{read_cpu_timer, tsc_info};
 
-#[repr(u8)]
-enum ProfPoint {
-    FileOpen = 0,
-    ReadToString,
-    ParsePairs,
-    // ParseNum,
-    Sum,
-    MiscOutput,
+const EARTH_RADIUS: f64 = 6372.8;
+
+// Only worth its cost per allocation where there are blocks to count against
+#[cfg(feature = "profile")]
+#[global_allocator]
+static ALLOCATOR: haversine::profile::ProfilingAllocator = haversine::profile::ProfilingAllocator(std::alloc::System);
+
+struct Input {
+    size: usize,
+    format: &'static str,
+    pairs: Vec<Pair>,
+    features: Vec<Feature>,
 }
 
-#[derive(Default, Copy, Clone)]
-struct Pair {
-    pub x0: f64,
-    pub y0: f64,
-    pub x1: f64,
-    pub y1: f64,
+/// Result of a command. Text output is printed as the command runs, the report is for --report json.
+struct Outcome {
+    report: Value,
+    passed: bool,
 }
 
-fn main() -> std::io::Result<()> {
-    let prof_begin = read_cpu_timer();
+fn read_input(path: &str) -> Result<String, Error> {
+    let io_error = |e| Error::Io(path.to_string(), e);
 
-    let args = env::args().collect::<Vec<String>>();
+    let input_file_size = {
+        time_block!("File::open");
+        let input_file = File::open(path).map_err(io_error)?;
+        input_file.metadata().map(|m| m.len() as usize).unwrap_or(0)
+    };
 
-    // 2 or 3 arguments required
-    if !(2..=3).contains(&args.len()) {
-        let exe_name = Path::new(&env::current_exe().unwrap())
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap()
-            .to_string();
+    time_bandwidth!("fs::read_to_string", input_file_size);
+    fs::read_to_string(path).map_err(io_error)
+}
 
-        println!("Usage: {exe_name} [haversine_input.json]");
-        println!("       {exe_name} [haversine_input.json] [haversine_answer.f64]");
+/// Parses into `pairs`, keeping its allocation for the pair formats. GeoJSON always allocates.
+fn parse_input_text_into(path: &Path, input: &str, format: InputFormat, pairs: &mut Vec<Pair>) -> Result<(&'static str, Vec<Feature>), Error> {
+    let format = match format {
+        InputFormat::Auto if geojson::is_geojson(path, input) => InputFormat::GeoJson,
+        InputFormat::Auto => InputFormat::Pairs(Format::detect(path, input)),
+        format => format,
+    };
 
-        return Ok(());
+    match format {
+        InputFormat::GeoJson => {
+            let geojson = parse_geojson(input).map_err(|e| Error::Input(format!("Malformed input GeoJSON: {e}")))?;
+            *pairs = geojson.pairs;
+            Ok(("geojson", geojson.features))
+        }
+        InputFormat::Pairs(format) => {
+            parse_input_into(format, input, pairs)
+                .ok_or_else(|| Error::Input(format!("Malformed input {}", format.name().to_uppercase())))?;
+            Ok((format.name(), Vec::new()))
+        }
+        InputFormat::Auto => unreachable!(),
     }
+}
 
-    let input_file_path = args[1].as_str();
-    let answer_file_path = args.get(2).map(|x| x.as_str());
-    
-    let input_file_size = {
-        time_block!("File::open", ProfPoint::FileOpen);
-        let input_file = File::open(input_file_path)?;
-        input_file.metadata().map(|m| m.len() as usize).unwrap_or(0)
-    };
-    
-    let input= {
-        time_bandwidth!("fs::read_to_string", ProfPoint::ReadToString, input_file_size);
-        fs::read_to_string(input_file_path)?
-    };
+fn parse_input_text(path: &Path, input: &str, format: InputFormat) -> Result<(&'static str, Vec<Pair>, Vec<Feature>), Error> {
+    let mut pairs = Vec::new();
+    let (format, features) = parse_input_text_into(path, input, format, &mut pairs)?;
+    Ok((format, pairs, features))
+}
 
-    let pairs = parse_pairs(&input).expect("ERROR: Malformed input JSON.");
+fn load_input(options: &Options) -> Result<Input, Error> {
+    let text = read_input(&options.input_path)?;
+    let (format, pairs, features) = parse_input_text(Path::new(&options.input_path), &text, options.input_format)?;
+    Ok(Input { size: text.len(), format, pairs, features })
+}
 
-    let mut distance_sum = 0.0;
-    {
-        time_bandwidth!("SumHaversineDistances", ProfPoint::Sum, pairs.len() * size_of::<Pair>());
-        let sum_coef = 1.0 / pairs.len() as f64;
-        for Pair { x0, y0, x1, y1 } in pairs.iter().cloned() {
-            let earth_radius = 6372.8;
-            let distance = haversine::reference_haversine(x0, y0, x1, y1, earth_radius);
-            distance_sum += distance * sum_coef;
+fn sum_distances(options: &Options, pairs: &[Pair]) -> f64 {
+    time_bandwidth!("SumHaversineDistances", size_of_val(pairs));
+    options.backend.sum_distances_parallel(pairs, EARTH_RADIUS, options.threads)
+}
+
+fn compute_distances(options: &Options, pairs: &[Pair]) -> Vec<f64> {
+    time_bandwidth!("ComputeDistances", size_of_val(pairs));
+    pairs.iter().map(|pair| options.backend.distance(pair, EARTH_RADIUS)).collect()
+}
+
+/// Members shared by every command's report.
+fn input_report(options: &Options, input: &Input) -> Vec<(String, Value)> {
+    let members = [
+        ("command", options.command.name().into()),
+        ("input", options.input_path.as_str().into()),
+        ("format", input.format.into()),
+        ("input_size", input.size.into()),
+        ("pair_count", input.pairs.len().into()),
+        ("math", options.backend.name().into()),
+        ("threads", options.threads.into()),
+    ];
+    members.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+fn compute(options: &Options, input: &Input) -> Result<Outcome, Error> {
+    let distance_sum = sum_distances(options, &input.pairs);
+    compute_report(options, input, distance_sum)
+}
+
+fn compute_report(options: &Options, input: &Input, distance_sum: f64) -> Result<Outcome, Error> {
+    time_block!("MiscOutput");
+
+    let feature_lengths = input.features.iter()
+        .map(|feature| haversine::sum_path_length(&input.pairs[feature.pairs.clone()], EARTH_RADIUS))
+        .collect::<Vec<_>>();
+
+    if options.report == ReportFormat::Text {
+        println!("Input size: {}", input.size);
+        println!("Pair count: {}", input.pairs.len());
+        println!("Haversine sum: {distance_sum:.16}");
+
+        if !input.features.is_empty() {
+            println!();
+            println!("Feature path lengths:");
+            for (feature, length) in input.features.iter().zip(&feature_lengths) {
+                println!("  {}: {length:.4} ({} segments)", feature.name, feature.pairs.len());
+            }
+        }
+    }
+
+    let mut report = input_report(options, input);
+    report.push(("sum".to_string(), distance_sum.into()));
+    if !input.features.is_empty() {
+        let features = input.features.iter().zip(&feature_lengths).map(|(feature, &length)| object([
+            ("name", feature.name.as_str().into()),
+            ("length", length.into()),
+            ("segments", feature.pairs.len().into()),
+        ]));
+        report.push(("features".to_string(), Value::Array(features.collect())));
+    }
+
+    Ok(Outcome { report: Value::Object(report), passed: true })
+}
+
+fn read_answers(path: &str) -> Result<AnswerFile, Error> {
+    AnswerFile::read(path).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => Error::Input(format!("{path}: {e}")),
+        _ => Error::Io(path.to_string(), e),
+    })
+}
+
+fn validate(options: &Options, input: &Input) -> Result<Outcome, Error> {
+    let answer_path = options.answer_path.as_deref().unwrap();
+    let answers = read_answers(answer_path)?;
+
+    // Validate the sum that is printed, not one recomputed on a different number of threads
+    let distance_sum = sum_distances(options, &input.pairs);
+    let mut outcome = compute_report(options, input, distance_sum)?;
+
+    let report = validate_pairs(&input.pairs, options.backend, EARTH_RADIUS, distance_sum, &answers, options.tolerance, options.worst_count);
+
+    if options.report == ReportFormat::Text {
+        println!();
+        println!("Validation:");
+
+        if !answers.is_legacy() {
+            let seed = answers.seed.map(|seed| seed.to_string()).unwrap_or("none".to_string());
+            println!("Answer file: v{}, {} distribution, seed {seed}", answers.version, answers.distribution.name());
+        }
+        if answers.earth_radius != EARTH_RADIUS {
+            println!("WARNING: answers use earth radius {}, not {EARTH_RADIUS}.", answers.earth_radius);
         }
+
+        report.print();
+        println!();
+    }
+
+    if let Value::Object(members) = &mut outcome.report {
+        members.push(("answers".to_string(), answer_path.into()));
+        members.push(("validation".to_string(), report.to_json()));
     }
-    
+    outcome.passed = report.passed();
+    Ok(outcome)
+}
+
+fn answers(options: &Options, input: &Input) -> Result<Outcome, Error> {
+    let distances = compute_distances(options, &input.pairs);
+    // From the stored distances, so the stored sum always matches them
+    let sum = options.backend.average_distances(&distances);
+
+    let out_path = options.out_path.clone().unwrap_or_else(|| {
+        Path::new(&options.input_path).with_extension("").to_string_lossy().into_owned() + "_answer.f64"
+    });
+
     {
-        time_block!("MiscOutput", ProfPoint::MiscOutput);
+        time_block!("WriteAnswers");
+        AnswerFile::new(distances, sum, EARTH_RADIUS).write(&out_path).map_err(|e| Error::Io(out_path.clone(), e))?;
+    }
 
-        println!("Input size: {}", input.len());
-        println!("Pair count: {}", pairs.len());
-        println!("Haversine sum: {distance_sum:.16}");
+    if options.report == ReportFormat::Text {
+        println!("Pair count: {}", input.pairs.len());
+        println!("Haversine sum: {sum:.16}");
+        println!("Answers: {out_path} ({} math)", options.backend.name());
+    }
 
-        let answers = if let Some(file) = answer_file_path {
-            Some(fs::read(file)?)
-        } else {
-            None
-        };
+    let mut report = input_report(options, input);
+    report.push(("sum".to_string(), sum.into()));
+    report.push(("answers".to_string(), out_path.into()));
+    Ok(Outcome { report: Value::Object(report), passed: true })
+}
 
-        if let Some(answers) = answers {
-            println!();
-            println!("Validation:");
+fn perf_event_names(events: &[PerfEvent]) -> Vec<&'static str> {
+    events.iter().map(|event| event.name()).collect()
+}
 
-            let ref_sum_idx = answers.len() - size_of::<f64>();
-            let num_answers = (ref_sum_idx) / size_of::<f64>();
+/// Writes the recorded trace, compact since it can get large, and returns its summary for the report.
+fn write_trace(path: &str, options: &Options, timer_freq: u64) -> Result<Value, Error> {
+    let traces = thread_traces();
+    fs::write(path, chrome_trace(&traces, timer_freq).to_string()).map_err(|e| Error::Io(path.to_string(), e))?;
 
-            if num_answers != pairs.len() {
-                println!("FAILED - pair count doesn't match {num_answers}.");
-            }
-            let reference_sum = f64::from_be_bytes(answers[ref_sum_idx..].try_into().unwrap());
+    let events = traces.iter().map(|trace| trace.events.len()).sum::<usize>();
+    let dropped = traces.iter().map(|trace| trace.dropped).sum::<u64>();
+    if options.report == ReportFormat::Text {
+        println!("Trace: {path} ({events} events on {} threads)", traces.len());
+        if dropped != 0 {
+            println!("WARNING: {dropped} events did not fit, raise --trace-capacity to keep them.");
+        }
+    }
 
-            println!("Reference sum: {reference_sum:.16}");
-            println!("Difference: {:.16}", distance_sum - reference_sum);
-            println!();
+    Ok(object([
+        ("path", path.into()),
+        ("events", events.into()),
+        ("dropped", dropped.into()),
+    ]))
+}
+
+/// Writes the call paths as folded stacks and as a flamegraph, whichever were asked for.
+fn write_stacks(options: &Options) -> Result<Vec<(String, Value)>, Error> {
+    let stacks = folded_stacks(&thread_time_records());
+    let text_report = options.report == ReportFormat::Text;
+    let mut members = Vec::new();
+
+    if let Some(path) = &options.folded_path {
+        let io_error = |e| Error::Io(path.clone(), e);
+        let mut out = BufWriter::new(File::create(path).map_err(io_error)?);
+        write_folded_stacks(&mut out, &stacks).and_then(|_| out.flush()).map_err(io_error)?;
+        if text_report {
+            println!("Folded stacks: {path} ({} stacks)", stacks.len());
+        }
+        members.push(("folded".to_string(), path.as_str().into()));
+    }
+
+    if let Some(path) = &options.flamegraph_path {
+        let title = format!("haversine {} {}", options.command.name(), options.input_path);
+        fs::write(path, render_flamegraph(&stacks, &title)).map_err(|e| Error::Io(path.clone(), e))?;
+        if text_report {
+            println!("Flamegraph: {path}");
         }
+        members.push(("flamegraph".to_string(), path.as_str().into()));
     }
-    
+
+    Ok(members)
+}
+
+/// Writes every profile export asked for, returning their report members.
+fn write_exports(options: &Options, total: u64, timer_freq: u64) -> Result<Vec<(String, Value)>, Error> {
+    let mut members = Vec::new();
+    if let Some(path) = &options.trace_path {
+        members.push(("trace".to_string(), write_trace(path, options, timer_freq)?));
+    }
+    members.extend(write_stacks(options)?);
+    if let Some(path) = &options.profile_out_path {
+        let io_error = |e| Error::Io(path.clone(), e);
+        let mut out = BufWriter::new(File::create(path).map_err(io_error)?);
+        write_time_records(&mut out, total, timer_freq, options.profile_format).and_then(|_| out.flush()).map_err(io_error)?;
+        if options.report == ReportFormat::Text {
+            println!("Profile records: {path} ({})", options.profile_format.name());
+        }
+        members.push(("profile_out".to_string(), path.as_str().into()));
+    }
+    if let Some(path) = &options.save_profile_path {
+        SavedProfile::from_records(&time_records(), total, timer_freq).write(path).map_err(|e| Error::Io(path.clone(), e))?;
+        if options.report == ReportFormat::Text {
+            println!("Profile: {path}");
+        }
+        members.push(("saved_profile".to_string(), path.as_str().into()));
+    }
+    Ok(members)
+}
+
+fn run(options: &Options) -> Result<Outcome, Error> {
+    match options.command {
+        Command::Bench => return bench::bench(options),
+        Command::Diff => return answer_diff::diff(options),
+        Command::ProfileDiff => return profile_diff::diff(options),
+        _ => {}
+    }
+
+    let input = load_input(options)?;
+    match options.command {
+        Command::Compute => compute(options, &input),
+        Command::Validate => validate(options, &input),
+        Command::Answers => answers(options, &input),
+        Command::Stats => distribution::stats(options, &input),
+        Command::Bench | Command::Diff | Command::ProfileDiff => unreachable!(),
+    }
+}
+
+fn main() -> ExitCode {
+    let prof_begin = read_cpu_timer();
+
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if args.is_empty() {
+        print_usage();
+        return Error::Usage(String::new()).exit_code();
+    }
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            print_usage();
+            return e.exit_code();
+        }
+    };
+
+    if options.trace_path.is_some() {
+        start_trace(options.trace_capacity);
+    }
+    track_page_faults(options.page_faults);
+    track_allocations(options.alloc_counts);
+    let perf_events = options.perf_counters.then(|| track_perf_counters(true));
+    track_durations(options.durations);
+    // After the features above are on, since they make every block dearer
+    calibrate_overhead();
+    let sampling = options.sample_hz.map(|hz| start_sampling(hz, SAMPLE_CAPACITY));
+
+    let mut outcome = match run(&options) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            return e.exit_code();
+        }
+    };
     let prof_end = read_cpu_timer();
-    let freq = estimate_cpu_frequency(1000);
+    stop_sampling();
 
+    // The repetition tester measures and reports its own timings, so bench only needs it for exports
+    let needs_freq = options.command != Command::Bench || options.trace_path.is_some() || options.save_profile_path.is_some() || options.profile_out_path.is_some();
+    let tsc = needs_freq.then(tsc_info);
+    let freq = tsc.map_or(0, |tsc| tsc.frequency.hz);
     let program_time = prof_end - prof_begin;
-    let program_time_ms = program_time as f64 * 1000.0 / freq as f64;
-    println!("Total time: {program_time_ms:.4}ms (CPU freq {freq}Hz)");
 
-    print_time_records(program_time, freq);
+    match write_exports(&options, program_time, freq) {
+        Ok(exports) => {
+            if let Value::Object(members) = &mut outcome.report {
+                members.extend(exports);
+            }
+        }
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            return e.exit_code();
+        }
+    }
+
+    if options.command == Command::Bench {
+        if options.report == ReportFormat::Json {
+            println!("{:#}", outcome.report);
+        }
+    } else {
+        let program_time_ms = program_time as f64 * 1000.0 / freq as f64;
 
-    println!();
+        match options.report {
+            ReportFormat::Text => {
+                let source = tsc.map_or("", |tsc| tsc.frequency.source.name());
+                println!("Total time: {program_time_ms:.4}ms (CPU freq {freq}Hz, {source})");
+                for warning in tsc.map(|tsc| tsc.warnings()).unwrap_or_default() {
+                    println!("WARNING: {warning}");
+                }
+                match &perf_events {
+                    Some(Ok(events)) => println!("Perf counters: {}", perf_event_names(events).join(", ")),
+                    Some(Err(e)) => println!("WARNING: perf counters unavailable: {e}"),
+                    None => {}
+                }
+                if let Some(Err(e)) = &sampling {
+                    println!("WARNING: sampling unavailable: {e}");
+                }
 
-    Ok(())
+                print_time_records(program_time, freq);
+
+                println!();
+            }
+            ReportFormat::Json => {
+                let mut report = outcome.report;
+                if let Value::Object(members) = &mut report {
+                    members.push(("total_ticks".to_string(), program_time.into()));
+                    members.push(("total_ms".to_string(), program_time_ms.into()));
+                    members.push(("cpu_freq".to_string(), freq.into()));
+                    if let Some(tsc) = tsc {
+                        members.push(("cpu_freq_source".to_string(), tsc.frequency.source.name().into()));
+                        members.push(("tsc_warnings".to_string(), tsc.warnings().into()));
+                    }
+                    if let Some(perf_events) = &perf_events {
+                        let names = perf_events.as_ref().map(|events| perf_event_names(events)).unwrap_or_default();
+                        members.push(("perf_events".to_string(), names.into()));
+                    }
+                    members.extend(profile_json(program_time, freq));
+                }
+                println!("{report:#}");
+            }
+        }
+    }
+
+    if outcome.passed {
+        ExitCode::SUCCESS
+    } else {
+        // Non-zero exit so scripts can gate on validation, or on regressions for profile-diff
+        if options.command == Command::ProfileDiff {
+            eprintln!("ERROR: Profile regressed");
+        } else {
+            eprintln!("ERROR: {}", Error::ValidationFailed);
+        }
+        Error::ValidationFailed.exit_code()
+    }
 }
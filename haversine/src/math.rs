@@ -0,0 +1,240 @@
+use std::f64::consts::{FRAC_PI_2, PI};
+use std::thread;
+
+use crate::{reference_haversine, Pair};
+
+/// Implementations of the haversine distance that can be swapped at runtime.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MathBackend {
+    /// `reference_haversine`, what the generator uses for its answers.
+    Reference,
+    /// Same formula with polynomial sin, cos and asin approximations.
+    Fast,
+    /// Vincenty's formula on the sphere, which stays accurate for tiny and near-antipodal distances,
+    /// summed with compensation. Meant for answer files rather than speed.
+    Precise,
+}
+
+impl MathBackend {
+    pub const ALL: [MathBackend; 3] = [MathBackend::Reference, MathBackend::Fast, MathBackend::Precise];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MathBackend::Reference => "reference",
+            MathBackend::Fast => "fast",
+            MathBackend::Precise => "precise",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MathBackend> {
+        MathBackend::ALL.into_iter().find(|backend| backend.name() == name)
+    }
+
+    #[inline]
+    pub fn distance(self, pair: &Pair, earth_radius: f64) -> f64 {
+        let &Pair { x0, y0, x1, y1 } = pair;
+        match self {
+            MathBackend::Reference => reference_haversine(x0, y0, x1, y1, earth_radius),
+            MathBackend::Fast => fast_haversine(x0, y0, x1, y1, earth_radius),
+            MathBackend::Precise => precise_haversine(x0, y0, x1, y1, earth_radius),
+        }
+    }
+
+    /// Average distance, in the same order and precision as `sum_haversine_distances`.
+    /// Precise sums the unscaled distances with compensation and divides at the end.
+    pub fn sum_distances(self, pairs: &[Pair], earth_radius: f64) -> f64 {
+        self.average(pairs.iter().map(|pair| self.distance(pair, earth_radius)), pairs.len())
+    }
+
+    /// Average of distances already computed, summed exactly as `sum_distances` sums them.
+    pub fn average_distances(self, distances: &[f64]) -> f64 {
+        self.average(distances.iter().copied(), distances.len())
+    }
+
+    #[inline]
+    fn average(self, distances: impl Iterator<Item = f64>, count: usize) -> f64 {
+        if self == MathBackend::Precise {
+            let mut sum = CompensatedSum::default();
+            for distance in distances {
+                sum.add(distance);
+            }
+            // An empty input averages to 0, as Reference's fold gives
+            return if count == 0 { 0.0 } else { sum.value() / count as f64 };
+        }
+
+        let sum_coef = 1.0 / count as f64;
+        distances.map(|distance| distance * sum_coef).fold(0.0, |sum, d| sum + d)
+    }
+
+    /// Splits the pairs into one contiguous chunk per thread, so the result can differ from
+    /// `sum_distances` in the last few bits. Precise always sums on one thread, so it never differs.
+    pub fn sum_distances_parallel(self, pairs: &[Pair], earth_radius: f64, threads: usize) -> f64 {
+        if threads <= 1 || pairs.len() < threads || self == MathBackend::Precise {
+            return self.sum_distances(pairs, earth_radius);
+        }
+
+        let sum_coef = 1.0 / pairs.len() as f64;
+        thread::scope(|scope| {
+            let chunks = pairs.chunks(pairs.len().div_ceil(threads)).map(|chunk| scope.spawn(move || {
+                chunk.iter().map(|pair| self.distance(pair, earth_radius) * sum_coef).fold(0.0, |sum, d| sum + d)
+            }));
+            // Collect first so every thread is started before joining
+            chunks.collect::<Vec<_>>().into_iter().map(|handle| handle.join().unwrap()).fold(0.0, |sum, d| sum + d)
+        })
+    }
+}
+
+/// Taylor series through x^13, accurate to ~1e-9 on [-pi/2, pi/2]. Valid for x in [-pi, pi].
+fn sin_approx(x: f64) -> f64 {
+    let x = if x > FRAC_PI_2 { PI - x } else if x < -FRAC_PI_2 { -PI - x } else { x };
+    let x2 = x * x;
+    x * (1.0 + x2 * (-1.0 / 6.0 + x2 * (1.0 / 120.0 + x2 * (-1.0 / 5040.0 + x2 * (1.0 / 362880.0
+        + x2 * (-1.0 / 39916800.0 + x2 * (1.0 / 6227020800.0)))))))
+}
+
+/// Valid for x in [-pi/2, pi/2], which covers latitudes.
+fn cos_approx(x: f64) -> f64 {
+    sin_approx(FRAC_PI_2 - x.abs())
+}
+
+/// Valid for x in [0, 1].
+fn asin_approx(x: f64) -> f64 {
+    // The series converges slowly near 1, so reflect the upper half onto [0, 0.5]
+    if x > 0.5 {
+        return FRAC_PI_2 - 2.0 * asin_approx(((1.0 - x) * 0.5).sqrt());
+    }
+
+    // Taylor series through x^21, coefficient n is (2n)! / (4^n (n!)^2 (2n + 1))
+    const COEFFICIENTS: [f64; 11] = [
+        1.0, 1.0 / 6.0, 3.0 / 40.0, 5.0 / 112.0, 35.0 / 1152.0, 63.0 / 2816.0, 231.0 / 13312.0,
+        143.0 / 10240.0, 6435.0 / 557056.0, 12155.0 / 1245184.0, 46189.0 / 5505024.0,
+    ];
+    let x2 = x * x;
+    x * COEFFICIENTS.iter().rev().fold(0.0, |acc, &c| acc * x2 + c)
+}
+
+pub fn fast_haversine(x0: f64, y0: f64, x1: f64, y1: f64, earth_radius: f64) -> f64 {
+    let d_lat = (y1 - y0).to_radians();
+    let d_lon = (x1 - x0).to_radians();
+    let lat1 = y0.to_radians();
+    let lat2 = y1.to_radians();
+
+    let sin_d_lat = sin_approx(d_lat / 2.0);
+    let sin_d_lon = sin_approx(d_lon / 2.0);
+    let a = sin_d_lat * sin_d_lat + cos_approx(lat1) * cos_approx(lat2) * sin_d_lon * sin_d_lon;
+    let c = 2.0 * asin_approx(a.sqrt().min(1.0));
+    earth_radius * c
+}
+
+/// Vincenty's formula with equal axes. atan2 is well conditioned for every distance, while the asin
+/// in the haversine formula loses precision as the points approach antipodes.
+pub fn precise_haversine(x0: f64, y0: f64, x1: f64, y1: f64, earth_radius: f64) -> f64 {
+    let (sin_lat1, cos_lat1) = y0.to_radians().sin_cos();
+    let (sin_lat2, cos_lat2) = y1.to_radians().sin_cos();
+    let (sin_d_lon, cos_d_lon) = (x1 - x0).to_radians().sin_cos();
+
+    let a = cos_lat2 * sin_d_lon;
+    let b = cos_lat1 * sin_lat2 - sin_lat1 * cos_lat2 * cos_d_lon;
+    let y = a.hypot(b);
+    let x = sin_lat1 * sin_lat2 + cos_lat1 * cos_lat2 * cos_d_lon;
+    earth_radius * y.atan2(x)
+}
+
+/// Neumaier's variant of Kahan summation, which also handles terms larger than the running sum.
+#[derive(Copy, Clone, Default)]
+pub struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    pub fn add(&mut self, x: f64) {
+        let t = self.sum + x;
+        if self.sum.abs() >= x.abs() {
+            self.compensation += (self.sum - t) + x;
+        } else {
+            self.compensation += (x - t) + self.sum;
+        }
+        self.sum = t;
+    }
+
+    pub fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approximations_are_close() {
+        for i in 0..=1000 {
+            let t = i as f64 / 1000.0;
+            let x = -PI + 2.0 * PI * t;
+            assert!((sin_approx(x) - x.sin()).abs() < 1e-8, "sin({x})");
+            assert!((cos_approx(x / 2.0) - (x / 2.0).cos()).abs() < 1e-8, "cos({x})");
+            assert!((asin_approx(t) - t.asin()).abs() < 1e-8, "asin({t})");
+        }
+    }
+
+    #[test]
+    fn fast_matches_reference() {
+        let pair = Pair { x0: -0.1276, y0: 51.5072, x1: 2.3522, y1: 48.8566 };
+        let reference = MathBackend::Reference.distance(&pair, 6372.8);
+        let fast = MathBackend::Fast.distance(&pair, 6372.8);
+        assert!((reference - fast).abs() < 1e-4, "{reference} vs {fast}");
+    }
+
+    #[test]
+    fn precise_matches_reference() {
+        let pair = Pair { x0: -0.1276, y0: 51.5072, x1: 2.3522, y1: 48.8566 };
+        let reference = MathBackend::Reference.distance(&pair, 6372.8);
+        let precise = MathBackend::Precise.distance(&pair, 6372.8);
+        assert!((reference - precise).abs() < 1e-9, "{reference} vs {precise}");
+
+        // Antipodes are exactly half the circumference
+        let antipodes = Pair { x0: 10.0, y0: 20.0, x1: -170.0, y1: -20.0 };
+        assert_eq!(MathBackend::Precise.distance(&antipodes, 1.0), PI);
+    }
+
+    #[test]
+    fn compensated_sum() {
+        let mut sum = CompensatedSum::default();
+        for x in [1.0, 1e100, 1.0, -1e100] {
+            sum.add(x);
+        }
+        assert_eq!(sum.value(), 2.0);
+    }
+
+    #[test]
+    fn parallel_sum_is_close() {
+        let pairs = (0..1001).map(|i| {
+            let t = i as f64 / 1001.0;
+            Pair { x0: -180.0 + 360.0 * t, y0: 90.0 - 180.0 * t, x1: 100.0 * t, y1: -45.0 * t }
+        }).collect::<Vec<_>>();
+
+        let serial = MathBackend::Reference.sum_distances(&pairs, 6372.8);
+        for threads in [1, 2, 7, 16] {
+            let parallel = MathBackend::Reference.sum_distances_parallel(&pairs, 6372.8, threads);
+            assert!((serial - parallel).abs() < 1e-9, "{threads} threads: {serial} vs {parallel}");
+        }
+    }
+
+    #[test]
+    fn empty_input_averages_to_zero() {
+        for backend in [MathBackend::Reference, MathBackend::Fast, MathBackend::Precise] {
+            assert_eq!(backend.sum_distances(&[], 6372.8), 0.0, "{}", backend.name());
+            assert_eq!(backend.sum_distances_parallel(&[], 6372.8, 4), 0.0, "{}", backend.name());
+        }
+    }
+
+    #[test]
+    fn averages_of_computed_distances_match() {
+        let pairs = (0..100).map(|i| Pair { x0: i as f64, y0: -(i as f64) / 2.0, x1: 180.0 - i as f64, y1: i as f64 / 3.0 }).collect::<Vec<_>>();
+        for backend in [MathBackend::Reference, MathBackend::Fast, MathBackend::Precise] {
+            let distances = pairs.iter().map(|pair| backend.distance(pair, 6372.8)).collect::<Vec<_>>();
+            assert_eq!(backend.average_distances(&distances).to_bits(), backend.sum_distances(&pairs, 6372.8).to_bits(), "{}", backend.name());
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::str::Chars;
+use haversine::format::Format;
 use haversine::time_function;
-
-use crate::{Pair, ProfPoint};
+use haversine::Pair;
 
 enum ParseState {
     X0,
@@ -22,11 +22,11 @@ impl ParseState {
 }
 
 fn get_digit(digit: char) -> Option<u8> {
-    (digit as u8).checked_sub(b'0')
+    (digit as u8).checked_sub(b'0').filter(|&digit| digit < 10)
 }
 
 pub fn parse_num(input: &mut Chars) -> Option<f64> {
-    //time_function!(ProfPoint::ParseNum);
+    //time_function!();
     
     // Parse number
     let mut valid_num = false;
@@ -64,14 +64,19 @@ pub fn parse_num(input: &mut Chars) -> Option<f64> {
     Some(num)
 }
 
-pub fn parse_pairs(input: &str) -> Option<Vec<Pair>> {
-    time_function!(ProfPoint::ParsePairs);
-    
-    let minimum_json_pair_encoding = 24 * 4; // Minimum 24 bytes per number encoding
-    let max_pair_count = input.len() / minimum_json_pair_encoding;
+/// A whole CSV or NDJSON field as a number, exponents included, failing on anything else in it.
+fn parse_field(field: &str) -> Option<f64> {
+    field.trim().parse().ok()
+}
 
-    let mut pairs = vec![Pair::default(); max_pair_count];
-    let mut pair_idx = 0;
+pub fn parse_pairs(input: &str, pairs: &mut Vec<Pair>) -> Option<()> {
+    time_function!();
+    
+    // Only a guess, compact JSON can take fewer bytes per pair
+    let typical_json_pair_encoding = 24 * 4; // 24 bytes per number encoding
+    pairs.clear();
+    pairs.reserve(input.len() / typical_json_pair_encoding);
+    let mut pair = Pair::default();
 
     let mut input = input.chars();
     let mut start_parsing = false;
@@ -94,17 +99,17 @@ pub fn parse_pairs(input: &str) -> Option<Vec<Pair>> {
 
             match state {
                 ParseState::X0 => {
-                    pairs[pair_idx].x0 = num;
+                    pair.x0 = num;
                 }
                 ParseState::Y0 => {
-                    pairs[pair_idx].y0 = num;
+                    pair.y0 = num;
                 }
                 ParseState::X1 => {
-                    pairs[pair_idx].x1 = num;
+                    pair.x1 = num;
                 }
                 ParseState::Y1 => {
-                    pairs[pair_idx].y1 = num;
-                    pair_idx += 1;
+                    pair.y1 = num;
+                    pairs.push(pair);
                 }
             }
 
@@ -112,6 +117,163 @@ pub fn parse_pairs(input: &str) -> Option<Vec<Pair>> {
         }
     }
 
-    pairs.truncate(pair_idx);
-    Some(pairs)
-}
\ No newline at end of file
+    Some(())
+}
+
+pub fn parse_csv_pairs(input: &str, pairs: &mut Vec<Pair>) -> Option<()> {
+    time_function!();
+
+    let mut lines = input.lines().filter(|line| !line.trim().is_empty()).peekable();
+
+    // Map columns by the header names if there is one, otherwise assume x0,y0,x1,y1
+    let mut columns = [0, 1, 2, 3];
+    if let Some(header) = lines.peek() {
+        // Not letters, which exponents have too, but fields that aren't numbers
+        if header.split(',').any(|field| parse_field(field).is_none()) {
+            let names = header.split(',').map(|name| name.trim().trim_matches('"')).collect::<Vec<_>>();
+            for (column, key) in columns.iter_mut().zip(["x0", "y0", "x1", "y1"]) {
+                *column = names.iter().position(|name| *name == key)?;
+            }
+            lines.next();
+        }
+    }
+
+    pairs.clear();
+    pairs.reserve(input.len() / (24 * 4));
+    let mut fields = Vec::with_capacity(4);
+    for line in lines {
+        fields.clear();
+        fields.extend(line.split(','));
+
+        let mut nums = [0.0; 4];
+        for (num, column) in nums.iter_mut().zip(columns) {
+            *num = parse_field(fields.get(column)?)?;
+        }
+        let [x0, y0, x1, y1] = nums;
+        pairs.push(Pair { x0, y0, x1, y1 });
+    }
+
+    Some(())
+}
+
+pub fn parse_ndjson_pairs(input: &str, pairs: &mut Vec<Pair>) -> Option<()> {
+    time_function!();
+
+    pairs.clear();
+    pairs.reserve(input.len() / (24 * 4));
+    for line in input.lines().filter(|line| !line.trim().is_empty()) {
+        let mut nums = [0.0; 4];
+        for (num, key) in nums.iter_mut().zip(["\"x0\"", "\"y0\"", "\"x1\"", "\"y1\""]) {
+            // Keys may appear in any order, the value follows the next :
+            let value = &line[line.find(key)? + key.len()..];
+            let value = &value[value.find(':')? + 1..];
+            *num = parse_field(&value[..value.find([',', '}']).unwrap_or(value.len())])?;
+        }
+        let [x0, y0, x1, y1] = nums;
+        pairs.push(Pair { x0, y0, x1, y1 });
+    }
+
+    Some(())
+}
+
+/// Parses into `pairs`, replacing its contents but keeping its allocation.
+pub fn parse_input_into(format: Format, input: &str, pairs: &mut Vec<Pair>) -> Option<()> {
+    match format {
+        Format::Json => parse_pairs(input, pairs),
+        Format::Csv => parse_csv_pairs(input, pairs),
+        Format::NdJson => parse_ndjson_pairs(input, pairs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_input(format: Format, input: &str) -> Option<Vec<Pair>> {
+        let mut pairs = Vec::new();
+        parse_input_into(format, input, &mut pairs)?;
+        Some(pairs)
+    }
+
+    fn generate_pairs(count: usize) -> Vec<Pair> {
+        // Small LCG so the test doesn't depend on the generator binary
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut random = |range: f64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 11) as f64 / (1u64 << 53) as f64) * 2.0 * range - range
+        };
+        (0..count).map(|_| Pair { x0: random(180.0), y0: random(90.0), x1: random(180.0), y1: random(90.0) }).collect()
+    }
+
+    fn write_pairs(format: Format, pairs: &[Pair]) -> String {
+        let mut out = String::new();
+        format.write_begin(&mut out);
+        for (i, pair) in pairs.iter().enumerate() {
+            format.write_pair(&mut out, pair, i == pairs.len() - 1);
+        }
+        format.write_end(&mut out);
+        out
+    }
+
+    #[test]
+    fn round_trip_sums_match_in_every_format() {
+        let pairs = generate_pairs(1000);
+        let earth_radius = 6372.8;
+
+        let reference = parse_input(Format::Csv, &write_pairs(Format::Csv, &pairs)).unwrap();
+        let reference_sum = haversine::sum_haversine_distances(&reference, earth_radius);
+
+        for format in Format::ALL {
+            let input = write_pairs(format, &pairs);
+            assert_eq!(Format::sniff(&input), format);
+
+            let parsed = parse_input(format, &input).unwrap();
+            assert_eq!(parsed.len(), reference.len(), "{}", format.name());
+            let sum = haversine::sum_haversine_distances(&parsed, earth_radius);
+
+            // The JSON fast path sums digits by hand, so it can be a few ULPs off the nearest double
+            if format == Format::Json {
+                for (parsed, reference) in parsed.iter().zip(&reference) {
+                    for (value, expected) in [(parsed.x0, reference.x0), (parsed.y0, reference.y0), (parsed.x1, reference.x1), (parsed.y1, reference.y1)] {
+                        assert!((value - expected).abs() < 1e-12, "{value} vs {expected}");
+                    }
+                }
+                assert!((sum - reference_sum).abs() < 1e-9);
+            } else {
+                assert_eq!(parsed, reference, "{}", format.name());
+                assert_eq!(sum.to_bits(), reference_sum.to_bits(), "{}", format.name());
+            }
+        }
+    }
+
+    #[test]
+    fn csv_columns_follow_header() {
+        let pairs = parse_input(Format::Csv, "y1,x1,y0,x0\n4,3,2,1\n").unwrap();
+        assert_eq!(pairs, [Pair { x0: 1.0, y0: 2.0, x1: 3.0, y1: 4.0 }]);
+
+        let pairs = parse_input(Format::Csv, "1,2,3,4\r\n-1.5,2,3,4").unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[1].x0, -1.5);
+    }
+
+    #[test]
+    fn exponents_round_trip() {
+        let pair = Pair { x0: 1.5e-3, y0: -2e10, x1: 6.25E-7, y1: 0.0 };
+        let csv = format!("{:e},{:e},{:E},{:e}\n", pair.x0, pair.y0, pair.x1, pair.y1);
+        let ndjson = format!("{{\"x0\":{:e}, \"y0\":{:e}, \"x1\":{:E}, \"y1\":{:e}}}\n", pair.x0, pair.y0, pair.x1, pair.y1);
+        let json = format!("{{\"pairs\":[{}]}}", ndjson.trim_end());
+        assert_eq!(Format::sniff(&json), Format::Json);
+
+        for (format, input) in [(Format::Csv, csv), (Format::NdJson, ndjson)] {
+            assert_eq!(parse_input(format, &input).unwrap(), [pair], "{}", format.name());
+        }
+        assert!(parse_input(Format::Csv, "1.5x,2,3,4\n").is_none());
+    }
+
+    #[test]
+    fn compact_json_parses_every_pair() {
+        let input = format!("{{\"pairs\":[{}]}}", [r#"{"x0":1,"y0":2,"x1":3,"y1":4}"#; 10].join(","));
+        assert!(input.len() / 10 < 96);
+        assert_eq!(parse_input(Format::Json, &input).unwrap(), [Pair { x0: 1.0, y0: 2.0, x1: 3.0, y1: 4.0 }; 10]);
+    }
+}
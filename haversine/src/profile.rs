@@ -1,5 +1,22 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use metrics::memory::read_os_page_fault_count;
+use metrics::output::OutputFormat;
+use metrics::perf::{PerfCounters, PerfCounts, PerfEvent};
+use metrics::sampling::{module_offset, SampleTimer};
 use metrics::timing::read_cpu_timer;
 
+use crate::json::{object, Value};
+use crate::profile_output::write_time_records;
+use crate::stats::LogHistogram;
+
 #[macro_export]
 macro_rules! function_name {
     () => {{
@@ -20,114 +37,1405 @@ pub use function_name;
 
 #[macro_export]
 macro_rules! time_bandwidth {
-    ($name:expr, $idx:expr, $bytes:expr) => {
-        let _time_block = $crate::profile::TimeBlock::new($name, $idx as usize, $bytes as u64);
+    ($name:expr, $bytes:expr) => {
+        let _time_block = {
+            // One anchor per call site, so sites sharing a label still get their own record
+            static ANCHOR: $crate::profile::Anchor = $crate::profile::Anchor::new();
+            $crate::profile::TimeBlock::new($name, ANCHOR.index(), $bytes as u64)
+        };
     };
 }
 pub use time_bandwidth;
 
 #[macro_export]
 macro_rules! time_block {
-    ($name:expr, $idx:expr) => {
-        $crate::time_bandwidth!($name, $idx, 0);
+    ($name:expr) => {
+        $crate::time_bandwidth!($name, 0);
     };
 }
 pub use time_block;
 
 #[macro_export]
 macro_rules! time_function {
-    ($idx:expr) => {
-        $crate::time_block!($crate::function_name!(), $idx);
+    () => {
+        $crate::time_block!($crate::function_name!());
     };
 }
 pub use time_function;
 
-struct TimeRecord {
-    label: &'static str,
-    elapsed_exclusive: u64, // Does not include children
-    elapsed_inclusive: u64, // Does include children
-    byte_count: u64,
-    hit_count: u64,
+const CHUNK_SIZE: usize = 256;
+const MAX_CHUNKS: usize = 256;
+pub const MAX_ANCHORS: usize = CHUNK_SIZE * MAX_CHUNKS;
+/// Distinct call paths per thread.
+pub const MAX_CALL_PATHS: usize = CHUNK_SIZE * MAX_CHUNKS;
+
+static NEXT_ANCHOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Record index of one profiled call site, assigned the first time the site is hit.
+pub struct Anchor(AtomicUsize); // Index + 1, 0 until assigned
+
+impl Anchor {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Anchor(AtomicUsize::new(0))
+    }
+
+    #[inline]
+    pub fn index(&self) -> usize {
+        match self.0.load(Ordering::Relaxed) {
+            0 => self.assign(),
+            index => index - 1,
+        }
+    }
+
+    #[cold]
+    fn assign(&self) -> usize {
+        let index = NEXT_ANCHOR.fetch_add(1, Ordering::Relaxed);
+        assert!(index < MAX_ANCHORS, "More than {MAX_ANCHORS} profiled call sites");
+
+        // If another thread got here first its index wins, and ours is left unused
+        match self.0.compare_exchange(0, index + 1, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => index,
+            Err(assigned) => assigned - 1,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TimeRecord {
+    pub label: &'static str,
+    pub elapsed_exclusive: u64, // Does not include children
+    pub elapsed_inclusive: u64, // Does include children
+    pub byte_count: u64,
+    pub hit_count: u64,
+    pub page_faults_exclusive: u64, // Process wide while the block was open, see track_page_faults
+    pub page_faults_inclusive: u64,
+    pub alloc_count: u64, // Made by the block itself, see ProfilingAllocator
+    pub alloc_bytes: u64,
+    pub perf: PerfCounts, // Inclusive, see track_perf_counters
+    pub durations: Option<LogHistogram>, // Inclusive time of each hit, see track_durations
+    pub samples: u64, // Taken while the block was the innermost one, see start_sampling
 }
 
 impl TimeRecord {
-    fn new(label: &'static str) -> TimeRecord {
-        TimeRecord { label, elapsed_exclusive: 0, elapsed_inclusive: 0, byte_count: 0, hit_count: 0 }
+    fn merge(&mut self, other: &TimeRecord) {
+        self.elapsed_exclusive = self.elapsed_exclusive.wrapping_add(other.elapsed_exclusive);
+        self.elapsed_inclusive += other.elapsed_inclusive;
+        self.byte_count += other.byte_count;
+        self.hit_count += other.hit_count;
+        self.page_faults_exclusive = self.page_faults_exclusive.wrapping_add(other.page_faults_exclusive);
+        self.page_faults_inclusive += other.page_faults_inclusive;
+        self.alloc_count += other.alloc_count;
+        self.alloc_bytes += other.alloc_bytes;
+        self.perf.add(&other.perf);
+        self.samples += other.samples;
+        match (&mut self.durations, &other.durations) {
+            (Some(durations), Some(other)) => durations.merge(other),
+            (None, Some(other)) => self.durations = Some(other.clone()),
+            _ => {}
+        }
     }
 }
 
-// Completely un-threadsafe, but it's fine for this use case. Don't need the locks overhead.
-static mut PARENT_TIME_RECORD: Option<usize> = None;
+/// A block as reached through the chain of blocks open above it. A recursive entry folds back into
+/// the node already open for its anchor, so no node has an ancestor with the same anchor, and
+/// inclusive times can be summed per anchor without counting anything twice.
+#[derive(Clone)]
+pub struct CallNode {
+    /// Index of the parent in the same list, None at the top level. Parents come before children.
+    pub parent: Option<usize>,
+    pub depth: usize,
+    pub anchor: usize,
+    pub record: TimeRecord,
+}
+
+/// Records of one thread that has hit at least one block.
+pub struct ThreadTimeRecords {
+    pub thread: String,
+    pub records: Vec<TimeRecord>,
+    pub call_tree: Vec<CallNode>,
+}
+
+/// One block as it ran, in timer ticks.
+#[derive(Copy, Clone)]
+pub struct TraceEvent {
+    pub label: &'static str,
+    pub start: u64,
+    pub end: u64,
+}
 
-const NUM_TIME_RECORDS: usize = 256;
-fn get_time_records() -> &'static mut [Option<TimeRecord>; NUM_TIME_RECORDS] {
-    const ARRAY_REPEAT_VALUE: Option<TimeRecord> = None;
-    
-    static mut TIME_BLOCK_RECORDS: [Option<TimeRecord>; NUM_TIME_RECORDS] = [ARRAY_REPEAT_VALUE; NUM_TIME_RECORDS];
-    unsafe {
-        &mut TIME_BLOCK_RECORDS
+/// Events of one thread in the order blocks ended. Events past the buffer capacity are counted in `dropped`.
+#[derive(Clone)]
+pub struct ThreadTrace {
+    pub thread: String,
+    pub tid: usize,
+    pub events: Vec<TraceEvent>,
+    pub dropped: u64,
+}
+
+// Capacity of each thread's event buffer, 0 while not tracing
+static TRACE_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static TRACE_START: AtomicU64 = AtomicU64::new(0);
+static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+
+/// Chunks are allocated on first use, so the table grows without moving entries that other
+/// threads may be reading.
+struct ChunkedTable<T> {
+    chunks: [OnceLock<Box<[T]>>; MAX_CHUNKS],
+}
+
+impl<T: Default> ChunkedTable<T> {
+    fn new() -> Self {
+        ChunkedTable { chunks: std::array::from_fn(|_| OnceLock::new()) }
+    }
+
+    #[inline]
+    fn get(&self, index: usize) -> &T {
+        let chunk = self.chunks[index / CHUNK_SIZE].get_or_init(|| (0..CHUNK_SIZE).map(|_| T::default()).collect());
+        &chunk[index % CHUNK_SIZE]
+    }
+
+    /// The entry if its chunk has been allocated, without allocating it.
+    fn try_get(&self, index: usize) -> Option<&T> {
+        Some(&self.chunks[index / CHUNK_SIZE].get()?[index % CHUNK_SIZE])
+    }
+
+    /// Entries of the allocated chunks up to the first unallocated one.
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().map_while(|chunk| chunk.get()).flat_map(|chunk| chunk.iter())
     }
 }
 
-pub fn print_time_records(total: u64, timer_freq: u64) {
-    let total_rcp = 100.0 / total as f64;
-    let time_records = get_time_records();
-    for record in time_records.iter().flatten() {
-        print!("  {}[{}]: {} ({:.2}%", record.label, record.hit_count, record.elapsed_exclusive, record.elapsed_exclusive as f64 * total_rcp);
-        
-        if record.elapsed_exclusive != record.elapsed_inclusive {
-            print!(", {:.2}% w/children", record.elapsed_inclusive as f64 * total_rcp);
-        }
-        
-        if record.byte_count != 0 {
-            const MEGABYTE: f64 = 1024.0 * 1024.0;
-            const GIGABYTE: f64 = 1024.0 * MEGABYTE;
-            
-            let seconds = record.elapsed_inclusive as f64 / timer_freq as f64;
-            let bytes_per_second = record.byte_count as f64 / seconds;
-            let megabytes = record.byte_count as f64 / MEGABYTE;
-            let gigabytes_per_second = bytes_per_second / GIGABYTE;
-            
-            print!(" {megabytes:.3}MB at {gigabytes_per_second:.2}GB/s");
-        }
-        
-        println!(")");
+// Only the owning thread ever writes, so a relaxed load and store is enough, and compiles to the
+// same plain moves as non-atomic code. Being atomic just lets another thread take a snapshot
+// without UB.
+#[inline]
+fn add(counter: &AtomicU64, value: u64) {
+    counter.store(counter.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+}
+
+#[derive(Default)]
+struct SharedNode {
+    /// Anchor index + 1, 0 while the slot is unused. Stored last, so readers that see it also see the parent.
+    anchor: AtomicUsize,
+    /// Node id of the parent, 0 at the top level. Node ids are slot index + 1.
+    parent: AtomicUsize,
+    elapsed_exclusive: AtomicU64,
+    elapsed_inclusive: AtomicU64,
+    byte_count: AtomicU64,
+    hit_count: AtomicU64,
+    page_faults_exclusive: AtomicU64,
+    page_faults_inclusive: AtomicU64,
+    alloc_count: AtomicU64,
+    alloc_bytes: AtomicU64,
+    samples: AtomicU64,
+}
+
+/// Inclusive perf counts of a node, indexed by event. Kept apart from SharedNode, which is hot.
+#[derive(Default)]
+struct NodePerf([AtomicU64; PerfEvent::COUNT]);
+
+/// Durations of each hit of a node, as LogHistogram buckets.
+struct NodeDurations {
+    counts: [AtomicU64; LogHistogram::BUCKET_COUNT],
+    max: AtomicU64,
+}
+
+impl Default for NodeDurations {
+    fn default() -> Self {
+        NodeDurations { counts: std::array::from_fn(|_| AtomicU64::new(0)), max: AtomicU64::new(0) }
+    }
+}
+
+impl NodeDurations {
+    fn snapshot(&self) -> Option<LogHistogram> {
+        let max = self.max.load(Ordering::Relaxed);
+        let counts = self.counts.iter().map(|count| count.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        counts.iter().any(|&count| count != 0).then_some(LogHistogram { counts, max })
+    }
+}
+
+#[derive(Default)]
+struct AnchorSlot {
+    label: OnceLock<&'static str>,
+    // Last parent node -> node lookup for this anchor, which is all a block in a loop ever needs
+    cached_parent: AtomicUsize,
+    cached_node: AtomicUsize, // 0 when nothing is cached
+    cached_node_ptr: AtomicPtr<SharedNode>,
+}
+
+#[derive(Default)]
+struct TraceBuffer {
+    events: Vec<TraceEvent>,
+    dropped: u64,
+}
+
+struct ThreadRecords {
+    thread: String,
+    tid: usize,
+    anchors: ChunkedTable<AnchorSlot>,
+    nodes: ChunkedTable<SharedNode>,
+    node_perf: ChunkedTable<NodePerf>, // Same ids as nodes
+    node_durations: ChunkedTable<NodeDurations>,
+    // Only contended while another thread copies the events out
+    trace: Mutex<TraceBuffer>,
+    perf: OnceLock<Option<PerfCounters>>, // None when they could not be opened
+}
+
+impl ThreadRecords {
+    fn new(thread: String) -> Self {
+        let tid = NEXT_TID.fetch_add(1, Ordering::Relaxed);
+        ThreadRecords { thread, tid, anchors: ChunkedTable::new(), nodes: ChunkedTable::new(), node_perf: ChunkedTable::new(), node_durations: ChunkedTable::new(), trace: Mutex::default(), perf: OnceLock::new() }
+    }
+
+    fn lock_trace(&self) -> std::sync::MutexGuard<'_, TraceBuffer> {
+        self.trace.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn reserve_trace(&self, capacity: usize) {
+        let mut trace = self.lock_trace();
+        let additional = capacity.saturating_sub(trace.events.len());
+        trace.events.reserve_exact(additional);
+    }
+
+    #[inline(never)]
+    fn push_trace_event(&self, event: TraceEvent, capacity: usize) {
+        let mut trace = self.lock_trace();
+        if trace.events.len() >= capacity {
+            trace.dropped += 1;
+            return;
+        }
+        // Threads that were already running when tracing started allocate on their first event
+        if trace.events.capacity() == 0 {
+            trace.events.reserve_exact(capacity);
+        }
+        trace.events.push(event);
+    }
+
+    fn trace(&self) -> ThreadTrace {
+        let trace = self.lock_trace();
+        ThreadTrace { thread: self.thread.clone(), tid: self.tid, events: trace.events.clone(), dropped: trace.dropped }
+    }
+
+    fn perf_counters(&self) -> Option<&PerfCounters> {
+        self.perf.get_or_init(|| PerfCounters::open().ok()).as_ref()
+    }
+
+    /// Counts at the start less the node's inclusive counts, so the end counts less this are the
+    /// new inclusive counts, which keeps recursion from counting anything twice, like time does.
+    #[inline(never)]
+    fn perf_start(&self, id: usize) -> Option<PerfCounts> {
+        let counters = self.perf_counters()?;
+        let node_perf = self.node_perf.get(id - 1);
+        let mut old_inclusive = PerfCounts::default();
+        for &event in counters.events() {
+            old_inclusive.set(event, node_perf.0[event as usize].load(Ordering::Relaxed));
+        }
+        Some(counters.read().ok()?.since(&old_inclusive))
+    }
+
+    #[inline(never)]
+    fn perf_end(&self, id: usize, perf_base: &PerfCounts) {
+        let Some(end) = self.perf_counters().and_then(|counters| counters.read().ok()) else { return };
+        let node_perf = self.node_perf.get(id - 1);
+        for (event, inclusive) in end.since(perf_base).iter() {
+            node_perf.0[event as usize].store(inclusive, Ordering::Relaxed);
+        }
+    }
+
+    /// Histograms for every node made so far. Chunks of the two tables cover the same ids.
+    fn reserve_durations(&self) {
+        let chunks = self.nodes.chunks.iter().take_while(|chunk| chunk.get().is_some()).count();
+        for chunk in 0..chunks {
+            self.node_durations.get(chunk * CHUNK_SIZE);
+        }
+    }
+
+    /// Skips nodes without a histogram, such as one another thread made while tracking was being
+    /// turned on, rather than allocating on the hot path.
+    #[inline]
+    fn record_duration(&self, id: usize, elapsed: u64) {
+        let Some(durations) = self.node_durations.try_get(id - 1) else { return };
+        add(&durations.counts[LogHistogram::bucket(elapsed)], 1);
+        if elapsed > durations.max.load(Ordering::Relaxed) {
+            durations.max.store(elapsed, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> Vec<CallNode> {
+        let perf_events = self.perf.get().and_then(Option::as_ref).map_or(&[][..], PerfCounters::events);
+        let mut tree = Vec::<CallNode>::new();
+        // Nodes are allocated in order, so the first unused slot ends the list
+        for (id, node) in (1..).zip(self.nodes.iter()) {
+            let Some(anchor) = node.anchor.load(Ordering::Acquire).checked_sub(1) else { break };
+            let parent = node.parent.load(Ordering::Relaxed).checked_sub(1);
+            tree.push(CallNode {
+                parent,
+                depth: parent.map_or(0, |parent| tree[parent].depth + 1),
+                anchor,
+                record: TimeRecord {
+                    label: self.anchors.get(anchor).label.get().copied().unwrap_or("?"),
+                    elapsed_exclusive: node.elapsed_exclusive.load(Ordering::Relaxed),
+                    elapsed_inclusive: node.elapsed_inclusive.load(Ordering::Relaxed),
+                    byte_count: node.byte_count.load(Ordering::Relaxed),
+                    hit_count: node.hit_count.load(Ordering::Relaxed),
+                    page_faults_exclusive: node.page_faults_exclusive.load(Ordering::Relaxed),
+                    page_faults_inclusive: node.page_faults_inclusive.load(Ordering::Relaxed),
+                    alloc_count: node.alloc_count.load(Ordering::Relaxed),
+                    alloc_bytes: node.alloc_bytes.load(Ordering::Relaxed),
+                    perf: {
+                        let mut perf = PerfCounts::default();
+                        for &event in perf_events {
+                            perf.set(event, self.node_perf.get(id - 1).0[event as usize].load(Ordering::Relaxed));
+                        }
+                        perf
+                    },
+                    durations: self.node_durations.try_get(id - 1).and_then(NodeDurations::snapshot),
+                    samples: node.samples.load(Ordering::Relaxed),
+                },
+            });
+        }
+        tree
+    }
+}
+
+/// Adds `from` into `into`, matching nodes by their path of anchors.
+fn merge_call_tree(into: &mut Vec<CallNode>, from: &[CallNode]) {
+    let mut merged_index = Vec::with_capacity(from.len());
+    for node in from {
+        let parent = node.parent.map(|parent| merged_index[parent]);
+        let index = match into.iter().position(|existing| existing.parent == parent && existing.anchor == node.anchor) {
+            Some(index) => {
+                into[index].record.merge(&node.record);
+                index
+            }
+            None => {
+                into.push(CallNode { parent, ..node.clone() });
+                into.len() - 1
+            }
+        };
+        merged_index.push(index);
+    }
+}
+
+/// Per-anchor totals, in the order anchors were first hit.
+fn flat_records(tree: &[CallNode]) -> Vec<TimeRecord> {
+    let mut records = Vec::<(usize, TimeRecord)>::new();
+    for node in tree {
+        match records.iter_mut().find(|(anchor, _)| *anchor == node.anchor) {
+            Some((_, record)) => record.merge(&node.record),
+            None => records.push((node.anchor, node.record.clone())),
+        }
+    }
+    records.sort_by_key(|(anchor, _)| *anchor);
+    records.into_iter().map(|(_, record)| record).collect()
+}
+
+struct Registry {
+    live: Vec<Arc<ThreadRecords>>,
+    /// Threads that have exited, merged by thread name so short-lived workers don't pile up.
+    retired: Vec<(String, Vec<CallNode>)>,
+    /// Traces of exited threads, kept per thread since they are timelines.
+    retired_traces: Vec<ThreadTrace>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { live: Vec::new(), retired: Vec::new(), retired_traces: Vec::new() });
+
+fn lock_registry() -> std::sync::MutexGuard<'static, Registry> {
+    // Records are only counters, so they are still worth printing after a panic
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct LocalProfile {
+    records: Arc<ThreadRecords>,
+    /// Node id of the innermost open block, 0 at the top level.
+    current: Cell<usize>,
+    node_count: Cell<usize>,
+    /// (parent node, anchor) -> node, for lookups the anchor cache misses.
+    children: RefCell<HashMap<(usize, usize), usize>>,
+    /// Leave nothing behind on exit, for the calibration thread.
+    discard: Cell<bool>,
+}
+
+impl LocalProfile {
+    fn register() -> Self {
+        let thread = thread::current().name().unwrap_or("<unnamed>").to_string();
+        let records = Arc::new(ThreadRecords::new(thread));
+        let trace_capacity = TRACE_CAPACITY.load(Ordering::Relaxed);
+        if trace_capacity != 0 {
+            records.reserve_trace(trace_capacity);
+        }
+        lock_registry().live.push(records.clone());
+        LocalProfile { records, current: Cell::new(0), node_count: Cell::new(0), children: RefCell::new(HashMap::new()), discard: Cell::new(false) }
+    }
+
+    #[cold]
+    fn find_node(&self, parent: usize, anchor: usize) -> usize {
+        *self.children.borrow_mut().entry((parent, anchor)).or_insert_with(|| {
+            // Recursion folds into the node already open for this anchor
+            let mut ancestor = parent;
+            while ancestor != 0 {
+                let node = self.records.nodes.get(ancestor - 1);
+                if node.anchor.load(Ordering::Relaxed) == anchor + 1 {
+                    return ancestor;
+                }
+                ancestor = node.parent.load(Ordering::Relaxed);
+            }
+
+            let id = self.node_count.get() + 1;
+            assert!(id <= MAX_CALL_PATHS, "More than {MAX_CALL_PATHS} profiled call paths");
+            self.node_count.set(id);
+
+            // Allocate histograms here, off the hot path. Nodes made before tracking started get theirs from track_durations.
+            if TRACK_DURATIONS.load(Ordering::Relaxed) {
+                self.records.node_durations.get(id - 1);
+            }
+
+            let node = self.records.nodes.get(id - 1);
+            node.parent.store(parent, Ordering::Relaxed);
+            node.anchor.store(anchor + 1, Ordering::Release);
+            id
+        })
+    }
+}
+
+impl Drop for LocalProfile {
+    fn drop(&mut self) {
+        if self.discard.get() {
+            lock_registry().live.retain(|records| !Arc::ptr_eq(records, &self.records));
+            return;
+        }
+
+        let snapshot = self.records.snapshot();
+        let trace = self.records.trace();
+        let mut registry = lock_registry();
+        if !trace.events.is_empty() || trace.dropped != 0 {
+            registry.retired_traces.push(trace);
+        }
+        registry.live.retain(|records| !Arc::ptr_eq(records, &self.records));
+        match registry.retired.iter_mut().find(|(thread, _)| *thread == self.records.thread) {
+            Some((_, retired)) => merge_call_tree(retired, &snapshot),
+            None => registry.retired.push((self.records.thread.clone(), snapshot)),
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL_PROFILE: LocalProfile = LocalProfile::register();
+    // Innermost open block, null at the top level. Kept apart from LOCAL_PROFILE since the allocator
+    // reads it, and this one never allocates or needs a destructor.
+    static ACTIVE_NODE: Cell<*const SharedNode> = const { Cell::new(ptr::null()) };
+}
+
+static TRACK_PAGE_FAULTS: AtomicBool = AtomicBool::new(false);
+
+/// Whether blocks opened from now on count page faults. Off by default, since reading the count is
+/// a system call on every block entry and exit. The count is for the whole process, so blocks on
+/// other threads add to it too.
+pub fn track_page_faults(enabled: bool) {
+    TRACK_PAGE_FAULTS.store(enabled, Ordering::Relaxed);
+}
+
+static TRACK_DURATIONS: AtomicBool = AtomicBool::new(false);
+
+/// Whether blocks ending from now on add their duration to a histogram of their call path, for
+/// percentiles. Histograms for the call paths of live threads are allocated here and new ones with
+/// their call paths, so recording never allocates.
+pub fn track_durations(enabled: bool) {
+    TRACK_DURATIONS.store(enabled, Ordering::Relaxed);
+    if enabled {
+        for records in &lock_registry().live {
+            records.reserve_durations();
+        }
+    }
+}
+
+static TRACK_PERF: AtomicBool = AtomicBool::new(false);
+
+/// Whether blocks opened from now on read hardware performance counters, or the software ones when
+/// the machine has none. Each thread opens its own counters on its first block. Returns the events
+/// the calling thread counts, or why none could be opened. Costs two system calls per block.
+pub fn track_perf_counters(enabled: bool) -> io::Result<Vec<PerfEvent>> {
+    TRACK_PERF.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        return Ok(Vec::new());
+    }
+
+    LOCAL_PROFILE.with(|local| {
+        let mut error = None;
+        let counters = local.records.perf.get_or_init(|| PerfCounters::open().map_err(|e| error = Some(e)).ok());
+        match (counters, error) {
+            (Some(counters), _) => Ok(counters.events().to_vec()),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(io::Error::other("opening them failed earlier")),
+        }
+    })
+}
+
+// Where each sample landed, written by the signal handler into slots allocated up front
+#[derive(Default)]
+struct SampleSlot {
+    ip: AtomicU64,
+    anchor: AtomicUsize, // Index + 1, 0 outside any block
+}
+
+static SAMPLE_SLOTS: OnceLock<Box<[SampleSlot]>> = OnceLock::new();
+static SAMPLE_COUNT: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_HZ: AtomicU64 = AtomicU64::new(0);
+static SAMPLER: Mutex<Option<SampleTimer>> = Mutex::new(None);
+
+// Runs in the SIGPROF handler: only atomics, a destructor-free thread-local and memory that
+// outlives the thread's blocks
+fn record_sample(ip: u64) {
+    // SAFETY: non-null only while a block of this thread is open, which keeps its node alive
+    let node = ACTIVE_NODE.try_with(|active| unsafe { active.get().as_ref() }).ok().flatten();
+    let anchor = node.map_or(0, |node| {
+        node.samples.fetch_add(1, Ordering::Relaxed);
+        node.anchor.load(Ordering::Relaxed)
+    });
+
+    let index = SAMPLE_COUNT.fetch_add(1, Ordering::Relaxed) as usize;
+    if let Some(slot) = SAMPLE_SLOTS.get().and_then(|slots| slots.get(index)) {
+        slot.ip.store(ip, Ordering::Relaxed);
+        slot.anchor.store(anchor, Ordering::Relaxed);
+    }
+}
+
+/// Interrupts the process `hz` times per second of CPU time and counts a sample against the
+/// innermost block of whichever thread was running, keeping the instruction pointer of the first
+/// `capacity` samples. Costs nothing per block, so it also sees the code between blocks. Sampling
+/// can only be started once per process, though a start that failed can be retried, keeping the
+/// first capacity. The kernel checks CPU timers on its scheduler tick, so rates above that come
+/// out at the tick rate.
+pub fn start_sampling(hz: u32, capacity: usize) -> io::Result<()> {
+    let mut sampler = SAMPLER.lock().unwrap_or_else(|e| e.into_inner());
+    if SAMPLE_HZ.load(Ordering::Relaxed) != 0 {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "sampling was already started"));
+    }
+    // The slots have to be there before the first signal
+    SAMPLE_SLOTS.get_or_init(|| (0..capacity).map(|_| SampleSlot::default()).collect());
+    let timer = SampleTimer::start(hz, record_sample)?;
+    SAMPLE_HZ.store(hz as u64, Ordering::Relaxed);
+    *sampler = Some(timer);
+    Ok(())
+}
+
+/// Stops sampling, so reporting doesn't add samples of its own.
+pub fn stop_sampling() {
+    SAMPLER.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Samples that landed on one instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotSpot {
+    pub ip: u64,
+    pub label: Option<&'static str>, // Innermost block, None outside any
+    pub samples: u64,
+}
+
+impl HotSpot {
+    /// `module+0xoffset`, for `addr2line`, or the bare address if it isn't in a loaded module.
+    pub fn location(&self) -> String {
+        match module_offset(self.ip) {
+            Some((module, offset)) => {
+                let module = module.rsplit('/').next().unwrap_or_default().to_string();
+                format!("{module}+{offset:#x}")
+            }
+            None => format!("{:#x}", self.ip),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleSummary {
+    pub hz: u64,
+    pub total: u64,
+    pub outside_blocks: u64,
+    /// Taken after the slots ran out, so counted per block but missing from `hot_spots`.
+    pub dropped: u64,
+    /// Most sampled first.
+    pub hot_spots: Vec<HotSpot>,
+}
+
+/// The samples so far, None if sampling was never started.
+pub fn samples() -> Option<SampleSummary> {
+    if SAMPLE_HZ.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let slots = SAMPLE_SLOTS.get()?;
+    let total = SAMPLE_COUNT.load(Ordering::Relaxed);
+    let kept = &slots[..(total as usize).min(slots.len())];
+
+    // Anchor indices are global, and labels are the same on every thread
+    let tree = call_tree();
+    let labels = tree.iter().map(|node| (node.anchor, node.record.label)).collect::<HashMap<_, _>>();
+    let mut counts = HashMap::<(u64, usize), u64>::new();
+    for slot in kept {
+        *counts.entry((slot.ip.load(Ordering::Relaxed), slot.anchor.load(Ordering::Relaxed))).or_default() += 1;
+    }
+
+    let mut hot_spots = counts
+        .into_iter()
+        .map(|((ip, anchor), samples)| HotSpot { ip, label: anchor.checked_sub(1).and_then(|anchor| labels.get(&anchor).copied()), samples })
+        .collect::<Vec<_>>();
+    hot_spots.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.ip.cmp(&b.ip)));
+
+    let in_blocks = tree.iter().map(|node| node.record.samples).sum::<u64>();
+    Some(SampleSummary {
+        hz: SAMPLE_HZ.load(Ordering::Relaxed),
+        total,
+        outside_blocks: total.saturating_sub(in_blocks),
+        dropped: total - kept.len() as u64,
+        hot_spots,
+    })
+}
+
+static TRACK_ALLOCATIONS: AtomicBool = AtomicBool::new(false);
+
+/// Whether an installed `ProfilingAllocator` counts allocations from now on. Off by default, when
+/// it only forwards to the allocator it wraps.
+pub fn track_allocations(enabled: bool) {
+    TRACK_ALLOCATIONS.store(enabled, Ordering::Relaxed);
+}
+
+/// Global allocator wrapper that counts allocations, and their requested bytes, against the
+/// innermost open block of the allocating thread, while `track_allocations` is on. A reallocation
+/// counts as an allocation of the new size. Install it with
+/// `#[global_allocator] static ALLOCATOR: ProfilingAllocator = ProfilingAllocator(System);`
+pub struct ProfilingAllocator<A = System>(pub A);
+
+#[inline]
+fn count_allocation(size: usize) {
+    if !TRACK_ALLOCATIONS.load(Ordering::Relaxed) {
+        return;
+    }
+    // try_with only fails while the thread is being torn down
+    let _ = ACTIVE_NODE.try_with(|active| {
+        // SAFETY: non-null only while a block of this thread is open, which keeps its node alive
+        if let Some(node) = unsafe { active.get().as_ref() } {
+            add(&node.alloc_count, 1);
+            add(&node.alloc_bytes, size as u64);
+        }
+    });
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ProfilingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        self.0.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        self.0.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(new_size);
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Records of every thread that has hit a block, live threads first, then exited ones by name.
+pub fn thread_time_records() -> Vec<ThreadTimeRecords> {
+    let registry = lock_registry();
+    let live = registry.live.iter().map(|records| (records.thread.clone(), records.snapshot()));
+    let retired = registry.retired.iter().cloned();
+
+    let overhead = overhead();
+    live.chain(retired)
+        .filter(|(_, call_tree)| !call_tree.is_empty())
+        .map(|(thread, mut call_tree)| {
+            if let Some(overhead) = overhead {
+                subtract_overhead(&mut call_tree, overhead);
+            }
+            ThreadTimeRecords { thread, records: flat_records(&call_tree), call_tree }
+        })
+        .collect()
+}
+
+/// Starts recording every block that ends from now on, up to `capacity` events per thread. The
+/// calling thread's buffer is allocated here and threads that start later allocate theirs when they
+/// register, so only threads that were already running allocate while recording.
+pub fn start_trace(capacity: usize) {
+    TRACE_START.store(read_cpu_timer(), Ordering::Relaxed);
+    LOCAL_PROFILE.with(|local| local.records.reserve_trace(capacity));
+    TRACE_CAPACITY.store(capacity, Ordering::Relaxed);
+}
+
+/// Recorded events of every thread, live threads first, then exited ones.
+pub fn thread_traces() -> Vec<ThreadTrace> {
+    let registry = lock_registry();
+    let live = registry.live.iter().map(|records| records.trace());
+    let retired = registry.retired_traces.iter().cloned();
+    live.chain(retired).filter(|trace| !trace.events.is_empty() || trace.dropped != 0).collect()
+}
+
+/// Chrome Trace Event document for `traces`, which loads in Perfetto and chrome://tracing.
+/// Times are in microseconds since `start_trace`.
+pub fn chrome_trace(traces: &[ThreadTrace], timer_freq: u64) -> Value {
+    let trace_start = TRACE_START.load(Ordering::Relaxed);
+    let micros = |ticks: u64| ticks as f64 * 1_000_000.0 / timer_freq as f64;
+
+    let mut events = Vec::new();
+    for trace in traces {
+        events.push(object([
+            ("name", "thread_name".into()),
+            ("ph", "M".into()),
+            ("pid", 1u64.into()),
+            ("tid", trace.tid.into()),
+            ("args", object([("name", trace.thread.as_str().into())])),
+        ]));
+
+        // Outer blocks end after their children, so sort by start for viewers that expect it
+        let mut sorted = trace.events.clone();
+        sorted.sort_by_key(|event| (event.start, std::cmp::Reverse(event.end)));
+        for event in sorted {
+            // Blocks that were already open when tracing started are cut at the start
+            let start = event.start.max(trace_start);
+            events.push(object([
+                ("name", event.label.into()),
+                ("cat", "profile".into()),
+                ("ph", "X".into()),
+                ("ts", micros(start - trace_start).into()),
+                ("dur", micros(event.end.saturating_sub(start)).into()),
+                ("pid", 1u64.into()),
+                ("tid", trace.tid.into()),
+            ]));
+        }
     }
+
+    let dropped = traces.iter().map(|trace| trace.dropped).sum::<u64>();
+    object([
+        ("traceEvents", Value::Array(events)),
+        ("displayTimeUnit", "ns".into()),
+        ("otherData", object([("cpu_freq", timer_freq.into()), ("dropped_events", dropped.into())])),
+    ])
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+fn read_timer() -> u64 {
+    read_cpu_timer()
+}
+
+// Tests can swap in a clock that advances a fixed amount per read, as if reading it took that long
+#[cfg(test)]
+thread_local! {
+    static FAKE_CLOCK: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+#[cfg(test)]
+const FAKE_READ_COST: u64 = 10;
+
+#[cfg(test)]
+fn read_timer() -> u64 {
+    FAKE_CLOCK.with(|clock| match clock.get() {
+        Some(now) => {
+            clock.set(Some(now + FAKE_READ_COST));
+            now
+        }
+        None => read_cpu_timer(),
+    })
+}
+
+/// Profiler cost per block hit, in timer ticks.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Overhead {
+    /// Lands between the block's own timer reads, so in its own time.
+    pub inner: f64,
+    /// Lands outside them, so in the parent's exclusive time.
+    pub outer: f64,
+}
+
+impl Overhead {
+    pub fn per_hit(&self) -> f64 {
+        self.inner + self.outer
+    }
+}
+
+static OVERHEAD: Mutex<Option<Overhead>> = Mutex::new(None);
+
+/// The overhead found by `calibrate_overhead`, None before calibrating.
+pub fn overhead() -> Option<Overhead> {
+    *OVERHEAD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+const CALIBRATION_ROUNDS: usize = 10;
+const CALIBRATION_ITERATIONS: u64 = 1000;
+
+/// Times empty blocks on the calling thread, returning the least cost per hit over a few rounds.
+/// The thread's records are thrown away when it exits.
+fn measure_overhead(iterations: u64) -> Overhead {
+    LOCAL_PROFILE.with(|local| local.discard.set(true));
+
+    let totals = || {
+        let tree = LOCAL_PROFILE.with(|local| local.records.snapshot());
+        let inclusive = |label| tree.iter().find(|node| node.record.label == label).map_or(0, |node| node.record.elapsed_inclusive);
+        (inclusive("profile_calibration_empty"), inclusive("profile_calibration"))
+    };
+
+    let mut best = Overhead { inner: f64::MAX, outer: f64::MAX };
+    let mut best_per_hit = f64::MAX;
+    let (mut last_empty, mut last_parent) = totals();
+    for _ in 0..CALIBRATION_ROUNDS {
+        {
+            crate::time_block!("profile_calibration");
+            for _ in 0..iterations {
+                crate::time_block!("profile_calibration_empty");
+            }
+        }
+
+        let (empty, parent) = totals();
+        // Each empty block costs its full overhead in the parent, which also has its own inner part
+        let inner = (empty - last_empty) as f64 / iterations as f64;
+        let per_hit = ((parent - last_parent) as f64 - inner) / iterations as f64;
+        best.inner = best.inner.min(inner);
+        best_per_hit = best_per_hit.min(per_hit);
+        (last_empty, last_parent) = (empty, parent);
+    }
+
+    best.outer = (best_per_hit - best.inner).max(0.0);
+    best
+}
+
+/// Measures what an empty block costs with the features enabled so far, on a separate thread so
+/// the calibration blocks don't show up, and subtracts it from all records read from now on.
+pub fn calibrate_overhead() -> Overhead {
+    let overhead = thread::spawn(|| measure_overhead(CALIBRATION_ITERATIONS)).join().unwrap();
+    *OVERHEAD.lock().unwrap_or_else(|e| e.into_inner()) = Some(overhead);
+    overhead
 }
 
+/// Removes the profiler's own cost from `tree`: `inner` for each hit of a node, plus the full cost of
+/// every hit below it from inclusive time, or `outer` of every direct child hit from exclusive time.
+pub fn subtract_overhead(tree: &mut [CallNode], overhead: Overhead) {
+    let mut child_hits = vec![0u64; tree.len()];
+    let mut descendant_hits = vec![0u64; tree.len()];
+    // Children come after their parents
+    for index in (0..tree.len()).rev() {
+        if let Some(parent) = tree[index].parent {
+            child_hits[parent] += tree[index].record.hit_count;
+            descendant_hits[parent] += tree[index].record.hit_count + descendant_hits[index];
+        }
+    }
+
+    let subtract = |ticks: u64, overhead: f64| (ticks as i64 as f64 - overhead).round().max(0.0) as u64;
+    for (index, node) in tree.iter_mut().enumerate() {
+        let own = node.record.hit_count as f64 * overhead.inner;
+        let record = &mut node.record;
+        record.elapsed_inclusive = subtract(record.elapsed_inclusive, own + descendant_hits[index] as f64 * overhead.per_hit());
+        record.elapsed_exclusive = subtract(record.elapsed_exclusive, own + child_hits[index] as f64 * overhead.outer);
+    }
+}
+
+/// Estimated total cost of the profiler for the blocks in `tree`.
+pub fn overhead_ticks(tree: &[CallNode], overhead: Overhead) -> u64 {
+    let hits = tree.iter().map(|node| node.record.hit_count).sum::<u64>();
+    (hits as f64 * overhead.per_hit()).round() as u64
+}
+
+/// Call paths of all threads merged together.
+pub fn call_tree() -> Vec<CallNode> {
+    let registry = lock_registry();
+    let mut tree = Vec::new();
+    for records in &registry.live {
+        merge_call_tree(&mut tree, &records.snapshot());
+    }
+    for (_, retired) in &registry.retired {
+        merge_call_tree(&mut tree, retired);
+    }
+    if let Some(overhead) = overhead() {
+        subtract_overhead(&mut tree, overhead);
+    }
+    tree
+}
+
+/// Every block hit so far, summed over all call paths and threads, in the order they were first hit.
+pub fn time_records() -> Vec<TimeRecord> {
+    flat_records(&call_tree())
+}
+
+/// Prints everything recorded so far in the human format, see `write_time_records`.
+pub fn print_time_records(total: u64, timer_freq: u64) {
+    let mut out = io::stdout().lock();
+    write_time_records(&mut out, total, timer_freq, OutputFormat::Human).expect("failed printing to stdout");
+}
+
+// Holds pointers into its thread's profile rather than looking them up again on drop. They stay
+// valid because blocks are !Send and the thread's records live until the thread exits.
 pub struct TimeBlock {
+    label: &'static str,
     start: u64,
     old_elapsed_inclusive: u64,
-    record: usize,
-    parent: Option<usize>,
+    start_page_faults: Option<u64>, // None when not tracking page faults
+    old_page_faults_inclusive: u64,
+    perf_base: Option<PerfCounts>, // None when not tracking perf counters, see perf_start
+    local: *const LocalProfile,
+    node: *const SharedNode,
+    parent: usize,
+    parent_node: *const SharedNode,
 }
 
 impl TimeBlock {
-    pub fn new(label: &'static str, record: usize, byte_count: u64) -> Self {
-        let time_records = get_time_records();
-        let time_record = time_records[record].get_or_insert(TimeRecord::new(label));
-        time_record.byte_count += byte_count;
-        
-        let parent = unsafe { PARENT_TIME_RECORD };
-        unsafe { PARENT_TIME_RECORD = Some(record) }
-        let old_elapsed_inclusive = time_records[record].as_ref().unwrap().elapsed_inclusive;
-        TimeBlock { start: read_cpu_timer(), old_elapsed_inclusive, record, parent }
+    pub fn new(label: &'static str, anchor: usize, byte_count: u64) -> Self {
+        LOCAL_PROFILE.with(|local| {
+            let slot = local.records.anchors.get(anchor);
+            let label = *slot.label.get_or_init(|| label);
+
+            let parent = local.current.get();
+            let cached = slot.cached_node.load(Ordering::Relaxed);
+            let (id, node) = if cached != 0 && slot.cached_parent.load(Ordering::Relaxed) == parent {
+                // SAFETY: cached from a node of this thread's table, which never moves or shrinks
+                (cached, unsafe { &*slot.cached_node_ptr.load(Ordering::Relaxed) })
+            } else {
+                let id = local.find_node(parent, anchor);
+                let node = local.records.nodes.get(id - 1);
+                slot.cached_parent.store(parent, Ordering::Relaxed);
+                slot.cached_node.store(id, Ordering::Relaxed);
+                slot.cached_node_ptr.store(ptr::from_ref(node).cast_mut(), Ordering::Relaxed);
+                (id, node)
+            };
+            let parent_node = ACTIVE_NODE.with(|active| active.replace(node));
+            local.current.set(id);
+
+            add(&node.byte_count, byte_count);
+
+            let old_elapsed_inclusive = node.elapsed_inclusive.load(Ordering::Relaxed);
+            let old_page_faults_inclusive = node.page_faults_inclusive.load(Ordering::Relaxed);
+            // Before the timer, so the system call is not timed as part of the block
+            let start_page_faults = TRACK_PAGE_FAULTS.load(Ordering::Relaxed).then(read_os_page_fault_count);
+            let perf_base = if TRACK_PERF.load(Ordering::Relaxed) { local.records.perf_start(id) } else { None };
+            TimeBlock {
+                label,
+                start: read_timer(),
+                old_elapsed_inclusive,
+                start_page_faults,
+                old_page_faults_inclusive,
+                perf_base,
+                local,
+                node,
+                parent,
+                parent_node,
+            }
+        })
     }
 }
 
 impl Drop for TimeBlock {
     fn drop(&mut self) {
-        let elapsed = read_cpu_timer() - self.start;
-        unsafe { PARENT_TIME_RECORD = self.parent }
-        
-        let time_records = get_time_records();
-        if let Some(parent_record) = self.parent {
-            let child_elapsed = &mut time_records[parent_record].as_mut().unwrap().elapsed_exclusive;
-            *child_elapsed = child_elapsed.wrapping_sub(elapsed);
-        }
-        let time_record = time_records[self.record].as_mut().unwrap();
-        time_record.elapsed_exclusive += elapsed;
-        time_record.elapsed_inclusive = self.old_elapsed_inclusive + elapsed;
-        time_record.hit_count += 1;
-    }
-}
\ No newline at end of file
+        let end = read_timer();
+        let elapsed = end - self.start;
+
+        // SAFETY: see TimeBlock, this is the thread that created the block and its profile is alive
+        let (local, node, parent_node) = unsafe { (&*self.local, &*self.node, self.parent_node.as_ref()) };
+        if let Some(perf_base) = &self.perf_base {
+            // The block's own node is still current
+            local.records.perf_end(local.current.get(), perf_base);
+        }
+        if TRACK_DURATIONS.load(Ordering::Relaxed) {
+            local.records.record_duration(local.current.get(), elapsed);
+        }
+        local.current.set(self.parent);
+        ACTIVE_NODE.with(|active| active.set(self.parent_node));
+
+        if let Some(parent_node) = parent_node {
+            add(&parent_node.elapsed_exclusive, elapsed.wrapping_neg());
+        }
+        // Children have already been subtracted (wrapping), so this can wrap back around
+        add(&node.elapsed_exclusive, elapsed);
+        node.elapsed_inclusive.store(self.old_elapsed_inclusive + elapsed, Ordering::Relaxed);
+        add(&node.hit_count, 1);
+
+        if let Some(start_page_faults) = self.start_page_faults {
+            let page_faults = read_os_page_fault_count().wrapping_sub(start_page_faults);
+            if let Some(parent_node) = parent_node {
+                add(&parent_node.page_faults_exclusive, page_faults.wrapping_neg());
+            }
+            add(&node.page_faults_exclusive, page_faults);
+            node.page_faults_inclusive.store(self.old_page_faults_inclusive + page_faults, Ordering::Relaxed);
+        }
+
+        let trace_capacity = TRACE_CAPACITY.load(Ordering::Relaxed);
+        if trace_capacity != 0 {
+            local.records.push_trace_event(TraceEvent { label: self.label, start: self.start, end }, trace_capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_node() -> usize {
+        LOCAL_PROFILE.with(|local| local.current.get())
+    }
+
+    /// Call paths of the calling thread with the given labels, as (label, parent label, hits).
+    fn local_paths(labels: &[&str]) -> Vec<(&'static str, Option<&'static str>, u64)> {
+        let tree = LOCAL_PROFILE.with(|local| local.records.snapshot());
+        tree.iter()
+            .filter(|node| labels.contains(&node.record.label))
+            .map(|node| (node.record.label, node.parent.map(|parent| tree[parent].record.label), node.record.hit_count))
+            .collect()
+    }
+
+    static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Puts a process-wide setting back as it was when dropped. Tests holding one run one at a
+    /// time, so none changes a setting under another.
+    struct Restore {
+        restore: Box<dyn Fn()>,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Restore {
+        fn with<R: Fn() + 'static>(save: impl FnOnce() -> R) -> Self {
+            let lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            Restore { restore: Box::new(save()), _lock: lock }
+        }
+
+        fn flag(flag: &'static AtomicBool) -> Self {
+            Self::with(|| {
+                let enabled = flag.load(Ordering::Relaxed);
+                move || flag.store(enabled, Ordering::Relaxed)
+            })
+        }
+
+        fn trace() -> Self {
+            Self::with(|| {
+                let capacity = TRACE_CAPACITY.load(Ordering::Relaxed);
+                move || TRACE_CAPACITY.store(capacity, Ordering::Relaxed)
+            })
+        }
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            (self.restore)();
+        }
+    }
+
+    #[test]
+    fn threads_are_recorded_separately() {
+        // Label and thread names unused elsewhere, since tests share the registry
+        let spawn = |name: &str, hits: u64| thread::Builder::new().name(name.to_string()).spawn(move || {
+            for _ in 0..hits {
+                crate::time_bandwidth!("profile_test", 2);
+            }
+        }).unwrap();
+
+        // Two threads with the same name are merged once they exit
+        for handle in [spawn("profile-test-a", 3), spawn("profile-test-a", 4), spawn("profile-test-b", 5)] {
+            handle.join().unwrap();
+        }
+
+        let threads = thread_time_records();
+        let hits = |name: &str| threads.iter().find(|thread| thread.thread == name)
+            .and_then(|thread| thread.records.iter().find(|record| record.label == "profile_test"))
+            .map(|record| (record.hit_count, record.byte_count));
+        assert_eq!(hits("profile-test-a"), Some((7, 14)));
+        assert_eq!(hits("profile-test-b"), Some((5, 10)));
+
+        let total = time_records().into_iter().find(|record| record.label == "profile_test").unwrap();
+        assert_eq!((total.hit_count, total.byte_count), (12, 24));
+    }
+
+    #[test]
+    fn sites_get_their_own_anchor() {
+        fn site_a() -> usize {
+            crate::time_block!("profile_same_label");
+            current_node()
+        }
+        fn site_b() -> usize {
+            crate::time_block!("profile_same_label");
+            current_node()
+        }
+
+        assert_ne!(site_a(), site_b());
+        assert_eq!(site_a(), site_a());
+        let same_label = time_records().into_iter().filter(|record| record.label == "profile_same_label").count();
+        assert_eq!(same_label, 2);
+    }
+
+    #[test]
+    fn table_grows_past_one_chunk() {
+        let anchors = (0..CHUNK_SIZE + 10).map(|_| Anchor::new()).collect::<Vec<_>>();
+        let indexes = anchors.iter().map(Anchor::index).collect::<Vec<_>>();
+        assert!(indexes.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let last = *indexes.last().unwrap();
+        assert!(last >= CHUNK_SIZE);
+        drop(TimeBlock::new("profile_last_anchor", last, 0));
+        assert!(time_records().iter().any(|record| record.label == "profile_last_anchor"));
+    }
+
+    #[test]
+    fn call_paths_are_separate() {
+        fn leaf() {
+            crate::time_block!("tree_leaf");
+        }
+        fn first() {
+            crate::time_block!("tree_first");
+            leaf();
+            leaf();
+        }
+        fn second() {
+            crate::time_block!("tree_second");
+            leaf();
+        }
+
+        first();
+        second();
+        first();
+
+        assert_eq!(local_paths(&["tree_leaf", "tree_first", "tree_second"]), [
+            ("tree_first", None, 2),
+            ("tree_leaf", Some("tree_first"), 4),
+            ("tree_second", None, 1),
+            ("tree_leaf", Some("tree_second"), 1),
+        ]);
+    }
+
+    #[test]
+    fn recursion_folds_into_one_node() {
+        fn recurse(depth: u32) {
+            crate::time_block!("tree_recurse");
+            if depth > 0 {
+                other(depth - 1);
+            }
+        }
+        fn other(depth: u32) {
+            crate::time_block!("tree_other");
+            recurse(depth);
+        }
+
+        let start = read_cpu_timer();
+        recurse(4);
+        let total = read_cpu_timer() - start;
+
+        assert_eq!(local_paths(&["tree_recurse", "tree_other"]), [
+            ("tree_recurse", None, 5),
+            ("tree_other", Some("tree_recurse"), 4),
+        ]);
+
+        let tree = LOCAL_PROFILE.with(|local| local.records.snapshot());
+        let recurse = tree.iter().find(|node| node.record.label == "tree_recurse").unwrap();
+        let other = tree.iter().find(|node| node.record.label == "tree_other").unwrap();
+        assert!(recurse.record.elapsed_inclusive <= total);
+        assert!(other.record.elapsed_inclusive < recurse.record.elapsed_inclusive);
+        let exclusive = recurse.record.elapsed_exclusive.wrapping_add(other.record.elapsed_exclusive);
+        assert_eq!(exclusive, recurse.record.elapsed_inclusive);
+    }
+
+    #[test]
+    fn trace_records_nested_blocks() {
+        let _restore = Restore::trace();
+        start_trace(16);
+        {
+            crate::time_block!("trace_outer");
+            crate::time_block!("trace_inner");
+        }
+        for _ in 0..20 {
+            crate::time_block!("trace_loop");
+        }
+
+        let tid = LOCAL_PROFILE.with(|local| local.records.tid);
+        let trace = thread_traces().into_iter().find(|trace| trace.tid == tid).unwrap();
+        assert_eq!((trace.events.len(), trace.dropped), (16, 6));
+
+        // Inner ends first and lies within outer
+        let [inner, outer] = [trace.events[0], trace.events[1]];
+        assert_eq!((inner.label, outer.label), ("trace_inner", "trace_outer"));
+        assert!(outer.start <= inner.start && inner.end <= outer.end);
+
+        let json = chrome_trace(&[trace], 1_000_000);
+        let events = json.get("traceEvents").and_then(Value::as_array).unwrap();
+        assert_eq!(events[0].get("ph").and_then(Value::as_str), Some("M"));
+        assert_eq!(events[1].get("name").and_then(Value::as_str), Some("trace_outer"));
+        assert_eq!(events.len(), 17);
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: ProfilingAllocator = ProfilingAllocator(System);
+
+    fn local_record(label: &str) -> TimeRecord {
+        let tree = LOCAL_PROFILE.with(|local| local.records.snapshot());
+        tree.into_iter().find(|node| node.record.label == label).unwrap().record
+    }
+
+    #[test]
+    fn allocations_go_to_the_innermost_block() {
+        let _restore = Restore::flag(&TRACK_ALLOCATIONS);
+        track_allocations(true);
+        {
+            crate::time_block!("alloc_outer");
+            std::hint::black_box(vec![0u8; 1000]);
+            {
+                crate::time_block!("alloc_inner");
+                for _ in 0..3 {
+                    std::hint::black_box(vec![0u8; 100]);
+                }
+            }
+        }
+
+        let outer = local_record("alloc_outer");
+        let inner = local_record("alloc_inner");
+        assert_eq!((outer.alloc_count, outer.alloc_bytes), (1, 1000));
+        assert_eq!((inner.alloc_count, inner.alloc_bytes), (3, 300));
+    }
+
+    #[test]
+    fn page_faults_are_exclusive_and_inclusive() {
+        const SIZE: usize = 16 * 1024 * 1024;
+        let _restore = Restore::flag(&TRACK_PAGE_FAULTS);
+        track_page_faults(true);
+        {
+            crate::time_block!("faults_outer");
+            crate::time_block!("faults_inner");
+            // Touch every page of fresh memory
+            let mut memory = Vec::<u8>::with_capacity(SIZE);
+            for offset in (0..SIZE).step_by(4096) {
+                unsafe { memory.as_mut_ptr().add(offset).write_volatile(1) };
+            }
+        }
+
+        let outer = local_record("faults_outer");
+        let inner = local_record("faults_inner");
+        assert!(inner.page_faults_inclusive >= (SIZE / (2 * 1024 * 1024)) as u64);
+        assert_eq!(inner.page_faults_inclusive, inner.page_faults_exclusive);
+        assert!(outer.page_faults_inclusive >= inner.page_faults_inclusive);
+        assert_eq!(outer.page_faults_exclusive.wrapping_add(inner.page_faults_exclusive), outer.page_faults_inclusive);
+    }
+
+    #[test]
+    fn perf_counters_are_inclusive() {
+        // Whether any counters open depends on the machine
+        let _restore = Restore::flag(&TRACK_PERF);
+        let Ok(events) = track_perf_counters(true) else { return };
+        {
+            crate::time_block!("perf_outer");
+            crate::time_block!("perf_inner");
+            let mut x = 0u64;
+            for i in 0..100_000 {
+                x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(i));
+            }
+        }
+
+        let outer = local_record("perf_outer");
+        let inner = local_record("perf_inner");
+        assert_eq!(inner.perf.iter().map(|(event, _)| event).collect::<Vec<_>>(), events);
+        for (event, count) in inner.perf.iter() {
+            assert!(outer.perf.get(event).unwrap() >= count, "{}", event.name());
+        }
+    }
+
+    #[test]
+    fn durations_give_percentiles() {
+        const LONG: u64 = 1_000_000;
+        let _restore = Restore::flag(&TRACK_DURATIONS);
+        track_durations(true);
+        for i in 0..100 {
+            crate::time_block!("durations_block");
+            if i % 10 == 0 {
+                let start = read_cpu_timer();
+                while read_cpu_timer() - start < LONG {}
+            }
+        }
+
+        let durations = local_record("durations_block").durations.unwrap();
+        assert_eq!(durations.count(), 100);
+        assert!(durations.quantile(0.5).unwrap() < LONG);
+        assert!(durations.quantile(0.99).unwrap() >= LONG - LONG / 16);
+        assert!(durations.max >= LONG);
+    }
+
+    #[test]
+    fn durations_cover_paths_seen_before_tracking() {
+        fn block() {
+            crate::time_block!("durations_early");
+        }
+
+        let _restore = Restore::flag(&TRACK_DURATIONS);
+        track_durations(false);
+        block();
+        track_durations(true);
+        block();
+
+        assert_eq!(local_record("durations_early").durations.unwrap().count(), 1);
+    }
+
+    #[test]
+    fn durations_skip_nodes_without_histograms() {
+        let records = ThreadRecords::new("no_histograms".to_string());
+        records.record_duration(1, 10);
+        assert!(records.node_durations.try_get(0).is_none());
+    }
+
+    #[test]
+    fn samples_go_to_the_innermost_block() {
+        // What the signal handler does, minus the signal
+        {
+            crate::time_block!("sample_outer");
+            record_sample(0x1000);
+            {
+                crate::time_block!("sample_inner");
+                record_sample(0x2000);
+                record_sample(0x2000);
+            }
+        }
+        record_sample(0x3000);
+
+        assert_eq!(local_record("sample_outer").samples, 1);
+        assert_eq!(local_record("sample_inner").samples, 2);
+
+        // A failed start leaves nothing to report
+        assert_eq!(start_sampling(0, 16).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        assert!(samples().is_none());
+    }
+
+    fn use_fake_clock() {
+        FAKE_CLOCK.with(|clock| clock.set(Some(0)));
+    }
+
+    fn advance_fake_clock(ticks: u64) {
+        FAKE_CLOCK.with(|clock| clock.set(clock.get().map(|now| now + ticks)));
+    }
+
+    #[test]
+    fn overhead_is_subtracted() {
+        let overhead = thread::spawn(|| {
+            use_fake_clock();
+            measure_overhead(100)
+        })
+        .join()
+        .unwrap();
+        // One read inside a block, the other outside
+        assert_eq!(overhead, Overhead { inner: FAKE_READ_COST as f64, outer: FAKE_READ_COST as f64 });
+
+        let mut tree = thread::spawn(|| {
+            use_fake_clock();
+            {
+                crate::time_block!("overhead_outer");
+                advance_fake_clock(1000);
+                for _ in 0..10 {
+                    crate::time_block!("overhead_inner");
+                    advance_fake_clock(50);
+                }
+            }
+            LOCAL_PROFILE.with(|local| local.records.snapshot())
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(tree[0].record.elapsed_inclusive, 1500 + 21 * FAKE_READ_COST);
+        subtract_overhead(&mut tree, overhead);
+        let times = tree.iter().map(|node| (node.record.label, node.record.elapsed_exclusive, node.record.elapsed_inclusive)).collect::<Vec<_>>();
+        assert_eq!(times, [("overhead_outer", 1000, 1500), ("overhead_inner", 500, 500)]);
+        assert_eq!(overhead_ticks(&tree, overhead), 11 * 2 * FAKE_READ_COST);
+    }
+}
@@ -0,0 +1,88 @@
+use haversine::json::{object, Value};
+use haversine::saved_profile::{diff_profiles, BlockDiff, SavedProfile};
+
+use crate::cli::{Error, Options, ReportFormat};
+use crate::Outcome;
+
+fn read_profile(path: &str) -> Result<SavedProfile, Error> {
+    SavedProfile::read(path).map_err(|e| Error::Io(path.to_string(), e))
+}
+
+fn format_change(change: Option<f64>) -> String {
+    change.map_or("-".to_string(), |change| format!("{:+.1}%", change * 100.0))
+}
+
+fn print_diff(diffs: &[BlockDiff], base: &SavedProfile, new: &SavedProfile) {
+    let (base_total, new_total) = (base.seconds(base.total_ticks), new.seconds(new.total_ticks));
+    println!(
+        "Total: {:.4}ms -> {:.4}ms ({})",
+        base_total * 1000.0,
+        new_total * 1000.0,
+        format_change((base.total_ticks != 0).then(|| new_total / base_total - 1.0)),
+    );
+    println!();
+
+    let width = diffs.iter().map(|diff| diff.label.len()).max().unwrap_or(0);
+    println!("  {:<width$} {:>12} {:>12} {:>9} {:>10}", "Block", "Base ms", "New ms", "Time", "Bandwidth");
+    for diff in diffs {
+        let ms = |block: &Option<_>, seconds: f64| if block.is_some() { format!("{:.4}", seconds * 1000.0) } else { "-".to_string() };
+        print!(
+            "  {:<width$} {:>12} {:>12} {:>9} {:>10}",
+            diff.label,
+            ms(&diff.base, diff.base_seconds),
+            ms(&diff.new, diff.new_seconds),
+            format_change(diff.time_change),
+            format_change(diff.bandwidth_change),
+        );
+        match (&diff.base, &diff.new) {
+            (None, Some(_)) => print!("  (new)"),
+            (Some(_), None) => print!("  (gone)"),
+            _ if diff.regressed => print!("  REGRESSED"),
+            _ => {}
+        }
+        println!();
+    }
+}
+
+fn block_diff_json(diff: &BlockDiff) -> Value {
+    object([
+        ("label", diff.label.as_str().into()),
+        ("base_ms", diff.base.as_ref().map(|_| diff.base_seconds * 1000.0).into()),
+        ("new_ms", diff.new.as_ref().map(|_| diff.new_seconds * 1000.0).into()),
+        ("time_change", diff.time_change.into()),
+        ("bandwidth_change", diff.bandwidth_change.into()),
+        ("regressed", diff.regressed.into()),
+    ])
+}
+
+/// Compares two saved profiles. The first is the input path, the second the answer path. Fails
+/// when any block is slower by more than the threshold.
+pub fn diff(options: &Options) -> Result<Outcome, Error> {
+    let base_path = options.input_path.as_str();
+    let new_path = options.answer_path.as_deref().unwrap();
+    let base = read_profile(base_path)?;
+    let new = read_profile(new_path)?;
+
+    let threshold = options.regression_threshold / 100.0;
+    let diffs = diff_profiles(&base, &new, threshold);
+    let regressions = diffs.iter().filter(|diff| diff.regressed).count();
+
+    if options.report == ReportFormat::Text {
+        println!("Base: {base_path}");
+        println!("New: {new_path}");
+        print_diff(&diffs, &base, &new);
+        println!();
+        println!("Regressions: {regressions} (threshold {}%)", options.regression_threshold);
+        println!();
+    }
+
+    let report = object([
+        ("command", options.command.name().into()),
+        ("base", base_path.into()),
+        ("new", new_path.into()),
+        ("threshold_percent", options.regression_threshold.into()),
+        ("regressions", regressions.into()),
+        ("blocks", Value::Array(diffs.iter().map(block_diff_json).collect())),
+    ]);
+    Ok(Outcome { report, passed: regressions == 0 })
+}
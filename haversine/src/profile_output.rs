@@ -0,0 +1,330 @@
+use std::io::{self, Write};
+
+use metrics::output::{csv_field, OutputFormat};
+use metrics::perf::PerfEvent;
+
+use crate::json::{object, Value};
+use crate::profile::{call_tree, overhead, overhead_ticks, samples, thread_time_records, time_records, CallNode, TimeRecord};
+
+const MEGABYTE: f64 = 1024.0 * 1024.0;
+const GIGABYTE: f64 = 1024.0 * MEGABYTE;
+const HOT_SPOT_COUNT: usize = 10;
+const JSON_HOT_SPOT_COUNT: usize = 20;
+
+fn gigabytes_per_second(record: &TimeRecord, timer_freq: u64) -> Option<f64> {
+    let seconds = record.elapsed_inclusive as f64 / timer_freq as f64;
+    (record.byte_count != 0).then(|| record.byte_count as f64 / (seconds * GIGABYTE))
+}
+
+fn write_time_record(out: &mut impl Write, record: &TimeRecord, total_rcp: f64, sample_rcp: f64, timer_freq: u64, indent: &str) -> io::Result<()> {
+    write!(out, "{indent}{}[{}]: {} ({:.2}%", record.label, record.hit_count, record.elapsed_exclusive, record.elapsed_exclusive as f64 * total_rcp)?;
+
+    if record.elapsed_exclusive != record.elapsed_inclusive {
+        write!(out, ", {:.2}% w/children", record.elapsed_inclusive as f64 * total_rcp)?;
+    }
+
+    if let Some(gigabytes_per_second) = gigabytes_per_second(record, timer_freq) {
+        let megabytes = record.byte_count as f64 / MEGABYTE;
+        write!(out, " {megabytes:.3}MB at {gigabytes_per_second:.2}GB/s")?;
+    }
+
+    if record.page_faults_inclusive != 0 {
+        write!(out, ", {} faults", record.page_faults_exclusive)?;
+        if record.page_faults_exclusive != record.page_faults_inclusive {
+            write!(out, " ({} w/children)", record.page_faults_inclusive)?;
+        }
+    }
+
+    if record.alloc_count != 0 {
+        write!(out, ", {} allocs of {:.3}MB", record.alloc_count, record.alloc_bytes as f64 / MEGABYTE)?;
+    }
+
+    if let Some(ipc) = record.perf.ipc() {
+        write!(out, ", IPC {ipc:.2}")?;
+    }
+    for event in [PerfEvent::BranchMisses, PerfEvent::CacheMisses, PerfEvent::ContextSwitches] {
+        let name = event.name().replace('_', " ");
+        match record.perf.get(event) {
+            Some(count) if record.byte_count != 0 && event != PerfEvent::ContextSwitches => {
+                write!(out, ", {:.4} {name}/byte", count as f64 / record.byte_count as f64)?
+            }
+            Some(count) if count != 0 => write!(out, ", {count} {name}")?,
+            _ => {}
+        }
+    }
+
+    // A single hit has no spread, and its time is already shown
+    if let Some(durations) = record.durations.as_ref().filter(|durations| durations.count() > 1) {
+        let quantile = |q| format_duration(durations.quantile(q).unwrap_or(0), timer_freq);
+        write!(out, ", p50 {} p90 {} p99 {} max {}", quantile(0.5), quantile(0.9), quantile(0.99), quantile(1.0))?;
+    }
+
+    // Comparable with the exclusive time percentage, since samples go to the innermost block
+    if record.samples != 0 {
+        write!(out, ", {} samples ({:.2}%)", record.samples, record.samples as f64 * sample_rcp)?;
+    }
+
+    writeln!(out, ")")
+}
+
+fn format_duration(ticks: u64, timer_freq: u64) -> String {
+    let seconds = ticks as f64 / timer_freq as f64;
+    if seconds >= 1.0 {
+        format!("{seconds:.3}s")
+    } else if seconds >= 1e-3 {
+        format!("{:.3}ms", seconds * 1e3)
+    } else if seconds >= 1e-6 {
+        format!("{:.3}us", seconds * 1e6)
+    } else {
+        format!("{:.0}ns", seconds * 1e9)
+    }
+}
+
+fn write_call_tree(out: &mut impl Write, tree: &[CallNode], parent: Option<usize>, total_rcp: f64, sample_rcp: f64, timer_freq: u64, indent: &str) -> io::Result<()> {
+    for (index, node) in tree.iter().enumerate().filter(|(_, node)| node.parent == parent) {
+        write_time_record(out, &node.record, total_rcp, sample_rcp, timer_freq, &format!("{indent}{}", "  ".repeat(node.depth)))?;
+        write_call_tree(out, tree, Some(index), total_rcp, sample_rcp, timer_freq, indent)?;
+    }
+    Ok(())
+}
+
+/// The call tree of all threads merged, then per thread if more than one thread was profiled, then
+/// the most sampled addresses if sampling was started.
+fn write_human(out: &mut impl Write, total: u64, timer_freq: u64) -> io::Result<()> {
+    let total_rcp = 100.0 / total as f64;
+    let samples = samples();
+    let sample_rcp = samples.as_ref().map_or(0.0, |samples| 100.0 / samples.total.max(1) as f64);
+    let tree = call_tree();
+    write_call_tree(out, &tree, None, total_rcp, sample_rcp, timer_freq, "  ")?;
+
+    if let Some(overhead) = overhead() {
+        let ticks = overhead_ticks(&tree, overhead);
+        writeln!(out, "  Profiler overhead: {ticks} ({:.2}%, {:.1} ticks per block)", ticks as f64 * total_rcp, overhead.per_hit())?;
+    }
+
+    let threads = thread_time_records();
+    if threads.len() > 1 {
+        for thread in threads {
+            writeln!(out, "  Thread {}:", thread.thread)?;
+            write_call_tree(out, &thread.call_tree, None, total_rcp, sample_rcp, timer_freq, "    ")?;
+        }
+    }
+
+    if let Some(samples) = samples {
+        write!(out, "  Samples: {} at {}Hz, {} ({:.2}%) outside blocks", samples.total, samples.hz, samples.outside_blocks, samples.outside_blocks as f64 * sample_rcp)?;
+        if samples.dropped != 0 {
+            write!(out, ", {} without an address", samples.dropped)?;
+        }
+        writeln!(out)?;
+        for hot_spot in samples.hot_spots.iter().take(HOT_SPOT_COUNT) {
+            let label = hot_spot.label.unwrap_or("outside blocks");
+            writeln!(out, "    {} in {label}: {} ({:.2}%)", hot_spot.location(), hot_spot.samples, hot_spot.samples as f64 * sample_rcp)?;
+        }
+    }
+    Ok(())
+}
+
+pub const CSV_HEADER: &str = "thread,path,label,depth,hits,exclusive_ticks,inclusive_ticks,exclusive_percent,inclusive_percent,\
+bytes,gb_per_second,page_faults_exclusive,page_faults_inclusive,alloc_count,alloc_bytes,ipc,samples,p50_ticks,p90_ticks,p99_ticks,max_ticks";
+
+fn write_csv_rows(out: &mut impl Write, thread: &str, tree: &[CallNode], total: u64, timer_freq: u64) -> io::Result<()> {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let mut paths = Vec::<String>::with_capacity(tree.len());
+    for node in tree {
+        let record = &node.record;
+        let path = match node.parent {
+            Some(parent) => format!("{};{}", paths[parent], record.label),
+            None => record.label.to_string(),
+        };
+        let quantile = |q| optional(record.durations.as_ref().and_then(|durations| durations.quantile(q)).map(|ticks| ticks.to_string()));
+
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(thread),
+            csv_field(&path),
+            csv_field(record.label),
+            node.depth,
+            record.hit_count,
+            record.elapsed_exclusive as i64,
+            record.elapsed_inclusive,
+            record.elapsed_exclusive as i64 as f64 * 100.0 / total as f64,
+            record.elapsed_inclusive as f64 * 100.0 / total as f64,
+            record.byte_count,
+            optional(gigabytes_per_second(record, timer_freq).map(|value| value.to_string())),
+            record.page_faults_exclusive as i64,
+            record.page_faults_inclusive,
+            record.alloc_count,
+            record.alloc_bytes,
+            optional(record.perf.ipc().map(|ipc| ipc.to_string())),
+            record.samples,
+            quantile(0.5),
+            quantile(0.9),
+            quantile(0.99),
+            optional(record.durations.as_ref().map(|durations| durations.max.to_string())),
+        )?;
+        paths.push(path);
+    }
+    Ok(())
+}
+
+/// One row per call path, with thread `all` for the merged tree and then each thread's own rows if
+/// more than one was profiled. Paths join labels from the top with `;`.
+fn write_csv(out: &mut impl Write, total: u64, timer_freq: u64) -> io::Result<()> {
+    writeln!(out, "{CSV_HEADER}")?;
+    write_csv_rows(out, "all", &call_tree(), total, timer_freq)?;
+    let threads = thread_time_records();
+    if threads.len() > 1 {
+        for thread in threads {
+            write_csv_rows(out, &thread.thread, &thread.call_tree, total, timer_freq)?;
+        }
+    }
+    Ok(())
+}
+
+/// One profiler record, with the same derived numbers the human output shows.
+pub fn time_record_json(record: &TimeRecord, total: u64, timer_freq: u64) -> Value {
+    let total_rcp = 100.0 / total as f64;
+    let mut members = vec![
+        ("label".to_string(), record.label.into()),
+        ("hits".to_string(), record.hit_count.into()),
+        ("exclusive_ticks".to_string(), record.elapsed_exclusive.into()),
+        ("inclusive_ticks".to_string(), record.elapsed_inclusive.into()),
+        ("exclusive_percent".to_string(), (record.elapsed_exclusive as f64 * total_rcp).into()),
+        ("inclusive_percent".to_string(), (record.elapsed_inclusive as f64 * total_rcp).into()),
+        ("bytes".to_string(), record.byte_count.into()),
+        ("page_faults_exclusive".to_string(), record.page_faults_exclusive.into()),
+        ("page_faults_inclusive".to_string(), record.page_faults_inclusive.into()),
+        ("alloc_count".to_string(), record.alloc_count.into()),
+        ("alloc_bytes".to_string(), record.alloc_bytes.into()),
+    ];
+
+    if !record.perf.is_empty() {
+        let counts = record.perf.iter().map(|(event, count)| (event.name().to_string(), count.into()));
+        members.push(("perf".to_string(), Value::Object(counts.collect())));
+        members.push(("ipc".to_string(), record.perf.ipc().into()));
+    }
+
+    if record.samples != 0 {
+        members.push(("samples".to_string(), record.samples.into()));
+    }
+
+    if let Some(durations) = &record.durations {
+        members.push(("duration_ticks".to_string(), object([
+            ("p50", durations.quantile(0.5).into()),
+            ("p90", durations.quantile(0.9).into()),
+            ("p99", durations.quantile(0.99).into()),
+            ("max", durations.max.into()),
+        ])));
+    }
+
+    if let Some(gigabytes_per_second) = gigabytes_per_second(record, timer_freq) {
+        members.push(("gb_per_second".to_string(), gigabytes_per_second.into()));
+    }
+
+    Value::Object(members)
+}
+
+pub fn time_records_json(records: &[TimeRecord], total: u64, timer_freq: u64) -> Value {
+    Value::Array(records.iter().map(|record| time_record_json(record, total, timer_freq)).collect())
+}
+
+/// Call tree nodes, parents first, each with the index of its parent.
+pub fn call_tree_json(tree: &[CallNode], total: u64, timer_freq: u64) -> Value {
+    Value::Array(tree.iter().map(|node| {
+        let mut record = time_record_json(&node.record, total, timer_freq);
+        if let Value::Object(members) = &mut record {
+            members.push(("parent".to_string(), node.parent.into()));
+            members.push(("depth".to_string(), node.depth.into()));
+        }
+        record
+    }).collect())
+}
+
+/// Everything recorded so far as report members: flat records, samples if sampling was started,
+/// the estimated profiler overhead, the merged call tree and each thread's records.
+pub fn profile_json(total: u64, timer_freq: u64) -> Vec<(String, Value)> {
+    let mut members = vec![("profile".to_string(), time_records_json(&time_records(), total, timer_freq))];
+
+    if let Some(samples) = samples() {
+        let hot_spots = samples.hot_spots.iter().take(JSON_HOT_SPOT_COUNT).map(|hot_spot| object([
+            ("location", hot_spot.location().into()),
+            ("label", hot_spot.label.into()),
+            ("samples", hot_spot.samples.into()),
+        ]));
+        members.push(("samples".to_string(), object([
+            ("hz", samples.hz.into()),
+            ("total", samples.total.into()),
+            ("outside_blocks", samples.outside_blocks.into()),
+            ("dropped", samples.dropped.into()),
+            ("hot_spots", Value::Array(hot_spots.collect())),
+        ])));
+    }
+
+    let tree = call_tree();
+    let overhead = overhead().unwrap_or_default();
+    members.push(("profiler_overhead".to_string(), object([
+        ("ticks_per_block", overhead.per_hit().into()),
+        ("ticks", overhead_ticks(&tree, overhead).into()),
+    ])));
+    members.push(("call_tree".to_string(), call_tree_json(&tree, total, timer_freq)));
+
+    let threads = thread_time_records().into_iter().map(|thread| object([
+        ("thread", thread.thread.into()),
+        ("records", time_records_json(&thread.records, total, timer_freq)),
+        ("call_tree", call_tree_json(&thread.call_tree, total, timer_freq)),
+    ]));
+    members.push(("profile_threads".to_string(), Value::Array(threads.collect())));
+    members
+}
+
+/// Writes everything recorded so far to `out`. Percentages are of `total` ticks, so rows of threads
+/// running in parallel can add up to more than 100%. `print_time_records` is the human format on
+/// stdout.
+pub fn write_time_records(out: &mut impl Write, total: u64, timer_freq: u64, format: OutputFormat) -> io::Result<()> {
+    match format {
+        OutputFormat::Human => write_human(out, total, timer_freq),
+        OutputFormat::Csv => write_csv(out, total, timer_freq),
+        OutputFormat::Json => {
+            let mut members = vec![("total_ticks".to_string(), total.into()), ("timer_freq".to_string(), timer_freq.into())];
+            members.extend(profile_json(total, timer_freq));
+            writeln!(out, "{:#}", Value::Object(members))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_each_format() {
+        std::thread::spawn(|| {
+            crate::time_bandwidth!("output_block", 1024);
+        })
+        .join()
+        .unwrap();
+        let profiled = cfg!(feature = "profile");
+
+        let write = |format| {
+            let mut out = Vec::new();
+            write_time_records(&mut out, 1_000_000, 1_000_000_000, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(write(OutputFormat::Human).contains("  output_block[1]: "), profiled);
+
+        let csv = write(OutputFormat::Csv);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+        let row = lines.find(|line| line.starts_with("all,output_block,output_block,0,1,"));
+        assert_eq!(row.is_some(), profiled);
+        if let Some(row) = row {
+            assert_eq!(row.split(',').count(), CSV_HEADER.split(',').count());
+        }
+
+        let json = crate::json::parse(&write(OutputFormat::Json)).unwrap();
+        let call_tree = json.get("call_tree").and_then(Value::as_array).unwrap();
+        assert_eq!(call_tree.iter().any(|node| node.get("label").and_then(Value::as_str) == Some("output_block")), profiled);
+    }
+}
@@ -1,13 +1,274 @@
+// Stands in for profile.rs when the profile feature is off. Every public item is here with the same
+// signature, so callers build either way, and blocks compile to nothing: arguments are type checked
+// but never evaluated. Functions report an empty profile.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::marker::PhantomData;
+
+use metrics::perf::{PerfCounts, PerfEvent};
+
+use crate::json::{object, Value};
+use crate::stats::LogHistogram;
+
+#[macro_export]
+macro_rules! function_name {
+    () => {{
+        fn f() {}
+        fn type_name_of<T>(_: T) -> &'static str {
+            std::any::type_name::<T>()
+        }
+        let name = type_name_of(f);
+
+        // Find and cut the rest of the path
+        match &name[..name.len() - 3].rfind(':') {
+            Some(pos) => &name[pos + 1..name.len() - 3],
+            None => &name[..name.len() - 3],
+        }
+    }};
+}
+pub use function_name;
+
+#[macro_export]
+macro_rules! time_bandwidth {
+    ($name:expr, $bytes:expr) => {
+        if false {
+            let _ = (&$name, &$bytes);
+        }
+    };
+}
+pub use time_bandwidth;
+
 #[macro_export]
 macro_rules! time_block {
-    ($name:expr, $idx:literal) => {};
+    ($name:expr) => {
+        $crate::time_bandwidth!($name, 0);
+    };
 }
 pub use time_block;
 
 #[macro_export]
 macro_rules! time_function {
-    ($idx:literal) => {};
+    () => {
+        $crate::time_block!($crate::function_name!());
+    };
 }
 pub use time_function;
 
-pub fn print_time_records(_: u64) {}
\ No newline at end of file
+pub const MAX_ANCHORS: usize = 256 * 256;
+pub const MAX_CALL_PATHS: usize = 256 * 256;
+
+pub struct Anchor;
+
+impl Anchor {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Anchor
+    }
+
+    #[inline(always)]
+    pub fn index(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Clone)]
+pub struct TimeRecord {
+    pub label: &'static str,
+    pub elapsed_exclusive: u64,
+    pub elapsed_inclusive: u64,
+    pub byte_count: u64,
+    pub hit_count: u64,
+    pub page_faults_exclusive: u64,
+    pub page_faults_inclusive: u64,
+    pub alloc_count: u64,
+    pub alloc_bytes: u64,
+    pub perf: PerfCounts,
+    pub durations: Option<LogHistogram>,
+    pub samples: u64,
+}
+
+#[derive(Clone)]
+pub struct CallNode {
+    pub parent: Option<usize>,
+    pub depth: usize,
+    pub anchor: usize,
+    pub record: TimeRecord,
+}
+
+pub struct ThreadTimeRecords {
+    pub thread: String,
+    pub records: Vec<TimeRecord>,
+    pub call_tree: Vec<CallNode>,
+}
+
+#[derive(Copy, Clone)]
+pub struct TraceEvent {
+    pub label: &'static str,
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Clone)]
+pub struct ThreadTrace {
+    pub thread: String,
+    pub tid: usize,
+    pub events: Vec<TraceEvent>,
+    pub dropped: u64,
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "built without the profile feature")
+}
+
+pub fn track_page_faults(_: bool) {}
+
+pub fn track_durations(_: bool) {}
+
+pub fn track_allocations(_: bool) {}
+
+pub fn track_perf_counters(_: bool) -> io::Result<Vec<PerfEvent>> {
+    Err(unsupported())
+}
+
+pub fn start_sampling(_: u32, _: usize) -> io::Result<()> {
+    Err(unsupported())
+}
+
+pub fn stop_sampling() {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotSpot {
+    pub ip: u64,
+    pub label: Option<&'static str>,
+    pub samples: u64,
+}
+
+impl HotSpot {
+    pub fn location(&self) -> String {
+        format!("{:#x}", self.ip)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleSummary {
+    pub hz: u64,
+    pub total: u64,
+    pub outside_blocks: u64,
+    pub dropped: u64,
+    pub hot_spots: Vec<HotSpot>,
+}
+
+pub fn samples() -> Option<SampleSummary> {
+    None
+}
+
+/// Passes everything straight to the wrapped allocator.
+pub struct ProfilingAllocator<A = System>(pub A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ProfilingAllocator<A> {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc_zeroed(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+pub fn thread_time_records() -> Vec<ThreadTimeRecords> {
+    Vec::new()
+}
+
+pub fn start_trace(_: usize) {}
+
+pub fn thread_traces() -> Vec<ThreadTrace> {
+    Vec::new()
+}
+
+pub fn chrome_trace(_: &[ThreadTrace], timer_freq: u64) -> Value {
+    object([
+        ("traceEvents", Value::Array(Vec::new())),
+        ("displayTimeUnit", "ns".into()),
+        ("otherData", object([("cpu_freq", timer_freq.into()), ("dropped_events", 0u64.into())])),
+    ])
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Overhead {
+    pub inner: f64,
+    pub outer: f64,
+}
+
+impl Overhead {
+    pub fn per_hit(&self) -> f64 {
+        self.inner + self.outer
+    }
+}
+
+pub fn overhead() -> Option<Overhead> {
+    None
+}
+
+pub fn calibrate_overhead() -> Overhead {
+    Overhead::default()
+}
+
+pub fn subtract_overhead(_: &mut [CallNode], _: Overhead) {}
+
+pub fn overhead_ticks(_: &[CallNode], _: Overhead) -> u64 {
+    0
+}
+
+pub fn call_tree() -> Vec<CallNode> {
+    Vec::new()
+}
+
+pub fn time_records() -> Vec<TimeRecord> {
+    Vec::new()
+}
+
+pub fn print_time_records(_: u64, _: u64) {}
+
+/// Not Send, like the real one.
+pub struct TimeBlock(PhantomData<*const ()>);
+
+impl TimeBlock {
+    #[inline(always)]
+    pub fn new(_: &'static str, _: usize, _: u64) -> Self {
+        TimeBlock(PhantomData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_do_nothing() {
+        fn evaluated() -> u64 {
+            panic!("block arguments must not be evaluated");
+        }
+
+        crate::time_function!();
+        crate::time_block!(crate::function_name!());
+        crate::time_bandwidth!("stub_block", evaluated());
+        assert_eq!(crate::function_name!(), "blocks_do_nothing");
+
+        assert!(call_tree().is_empty() && time_records().is_empty() && thread_time_records().is_empty());
+        assert!(samples().is_none() && overhead().is_none());
+        assert_eq!(track_perf_counters(true).err().map(|e| e.kind()), Some(io::ErrorKind::Unsupported));
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::json::{self, object, Value};
+use crate::profile::TimeRecord;
+
+/// Format version written to `version`, bumped when fields change meaning.
+pub const PROFILE_VERSION: u64 = 1;
+
+/// One block of a saved profile, in ticks of the profile's timer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedBlock {
+    pub label: String,
+    pub hits: u64,
+    pub exclusive_ticks: u64,
+    pub inclusive_ticks: u64,
+    pub bytes: u64,
+}
+
+/// Profiler records as saved by `--save-profile`, so runs can be compared later, even across
+/// machines: everything is converted to seconds with the profile's own timer frequency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedProfile {
+    pub timer_freq: u64,
+    pub total_ticks: u64,
+    pub blocks: Vec<SavedBlock>,
+}
+
+impl SavedProfile {
+    /// Blocks sharing a label, from different call sites, are saved as one.
+    pub fn from_records(records: &[TimeRecord], total_ticks: u64, timer_freq: u64) -> SavedProfile {
+        let mut blocks = Vec::<SavedBlock>::new();
+        for record in records {
+            let block = match blocks.iter_mut().position(|block| block.label == record.label) {
+                Some(index) => &mut blocks[index],
+                None => {
+                    blocks.push(SavedBlock { label: record.label.to_string(), hits: 0, exclusive_ticks: 0, inclusive_ticks: 0, bytes: 0 });
+                    blocks.last_mut().unwrap()
+                }
+            };
+            block.hits += record.hit_count;
+            block.exclusive_ticks = block.exclusive_ticks.wrapping_add(record.elapsed_exclusive);
+            block.inclusive_ticks += record.elapsed_inclusive;
+            block.bytes += record.byte_count;
+        }
+        // Exclusive times can come out slightly negative from timer noise
+        for block in &mut blocks {
+            block.exclusive_ticks = (block.exclusive_ticks as i64).max(0) as u64;
+        }
+        SavedProfile { timer_freq, total_ticks, blocks }
+    }
+
+    pub fn seconds(&self, ticks: u64) -> f64 {
+        ticks as f64 / self.timer_freq as f64
+    }
+
+    pub fn block(&self, label: &str) -> Option<&SavedBlock> {
+        self.blocks.iter().find(|block| block.label == label)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let blocks = self.blocks.iter().map(|block| object([
+            ("label", block.label.as_str().into()),
+            ("hits", block.hits.into()),
+            ("exclusive_ticks", block.exclusive_ticks.into()),
+            ("inclusive_ticks", block.inclusive_ticks.into()),
+            ("bytes", block.bytes.into()),
+        ]));
+        object([
+            ("version", PROFILE_VERSION.into()),
+            ("timer_freq", self.timer_freq.into()),
+            ("total_ticks", self.total_ticks.into()),
+            ("blocks", Value::Array(blocks.collect())),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Result<SavedProfile, String> {
+        let number = |value: &Value, key: &str| {
+            value.get(key).and_then(Value::as_f64).filter(|n| *n >= 0.0).map(|n| n as u64).ok_or_else(|| format!("Missing or negative {key}"))
+        };
+
+        let version = number(value, "version")?;
+        if version != PROFILE_VERSION {
+            return Err(format!("Unsupported profile version {version}"));
+        }
+        let timer_freq = number(value, "timer_freq")?;
+        if timer_freq == 0 {
+            return Err("timer_freq is 0".to_string());
+        }
+
+        let blocks = value.get("blocks").and_then(Value::as_array).ok_or("Missing blocks")?;
+        let blocks = blocks
+            .iter()
+            .map(|block| {
+                Ok(SavedBlock {
+                    label: block.get("label").and_then(Value::as_str).ok_or("Block without a label")?.to_string(),
+                    hits: number(block, "hits")?,
+                    exclusive_ticks: number(block, "exclusive_ticks")?,
+                    inclusive_ticks: number(block, "inclusive_ticks")?,
+                    bytes: number(block, "bytes")?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(SavedProfile { timer_freq, total_ticks: number(value, "total_ticks")?, blocks })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<SavedProfile> {
+        let text = fs::read_to_string(path)?;
+        let value = json::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        SavedProfile::from_json(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, format!("{:#}\n", self.to_json()))
+    }
+}
+
+/// One label compared across two profiles. Changes are relative, 0.1 being 10% more.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockDiff {
+    pub label: String,
+    pub base: Option<SavedBlock>,
+    pub new: Option<SavedBlock>,
+    pub base_seconds: f64, // Exclusive
+    pub new_seconds: f64,
+    pub time_change: Option<f64>,
+    pub bandwidth_change: Option<f64>, // Bytes per inclusive second, for blocks that count bytes in both
+    pub regressed: bool,
+}
+
+/// Blocks below this share of the base profile's total time are too noisy to fail a comparison.
+pub const MIN_REGRESSION_SHARE: f64 = 0.01;
+
+/// Compares exclusive time and bandwidth of every label in either profile, base order first.
+/// A block regresses when its exclusive time grows by more than `threshold` (0.05 for 5%), or its
+/// bandwidth drops by that much, unless it took under `MIN_REGRESSION_SHARE` of the base total.
+pub fn diff_profiles(base: &SavedProfile, new: &SavedProfile, threshold: f64) -> Vec<BlockDiff> {
+    let mut labels = base.blocks.iter().map(|block| block.label.as_str()).collect::<Vec<_>>();
+    labels.extend(new.blocks.iter().map(|block| block.label.as_str()).filter(|label| base.block(label).is_none()));
+
+    let base_total = base.seconds(base.total_ticks);
+    labels
+        .into_iter()
+        .map(|label| {
+            let (base_block, new_block) = (base.block(label), new.block(label));
+            let exclusive = |profile: &SavedProfile, block: Option<&SavedBlock>| block.map_or(0.0, |block| profile.seconds(block.exclusive_ticks));
+            let (base_seconds, new_seconds) = (exclusive(base, base_block), exclusive(new, new_block));
+
+            let bandwidth = |profile: &SavedProfile, block: &SavedBlock| block.bytes as f64 / profile.seconds(block.inclusive_ticks);
+            let (time_change, bandwidth_change) = match (base_block, new_block) {
+                (Some(base_block), Some(new_block)) => (
+                    (base_seconds > 0.0).then(|| new_seconds / base_seconds - 1.0),
+                    (base_block.bytes != 0 && new_block.bytes != 0 && base_block.inclusive_ticks != 0 && new_block.inclusive_ticks != 0)
+                        .then(|| bandwidth(new, new_block) / bandwidth(base, base_block) - 1.0),
+                ),
+                _ => (None, None),
+            };
+
+            let significant = base_seconds >= base_total * MIN_REGRESSION_SHARE;
+            let regressed = significant && (time_change.is_some_and(|change| change > threshold) || bandwidth_change.is_some_and(|change| change < -threshold));
+            BlockDiff {
+                label: label.to_string(),
+                base: base_block.cloned(),
+                new: new_block.cloned(),
+                base_seconds,
+                new_seconds,
+                time_change,
+                bandwidth_change,
+                regressed,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(label: &str, exclusive_ticks: u64, inclusive_ticks: u64, bytes: u64) -> SavedBlock {
+        SavedBlock { label: label.to_string(), hits: 1, exclusive_ticks, inclusive_ticks, bytes }
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let profile = SavedProfile { timer_freq: 3_000_000_000, total_ticks: 1000, blocks: vec![block("parse", 600, 900, 4096)] };
+        let text = profile.to_json().to_string();
+        assert_eq!(SavedProfile::from_json(&json::parse(&text).unwrap()), Ok(profile));
+        assert!(SavedProfile::from_json(&json::parse(r#"{"version": 2}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn diff_flags_regressions() {
+        // The new run has a timer twice as fast, so equal seconds are twice the ticks
+        let base = SavedProfile {
+            timer_freq: 1000,
+            total_ticks: 10_000,
+            blocks: vec![block("read", 1000, 1000, 1000), block("parse", 5000, 5000, 0), block("tiny", 10, 10, 0), block("gone", 100, 100, 0)],
+        };
+        let new = SavedProfile {
+            timer_freq: 2000,
+            total_ticks: 20_000,
+            blocks: vec![block("read", 2000, 2000, 500), block("parse", 12_000, 12_000, 0), block("tiny", 100, 100, 0), block("added", 100, 100, 0)],
+        };
+
+        let diff = diff_profiles(&base, &new, 0.05);
+        let percent = |change: Option<f64>| change.map(|change| (change * 100.0).round());
+        let summary = diff.iter().map(|diff| (diff.label.as_str(), percent(diff.time_change), diff.regressed)).collect::<Vec<_>>();
+        assert_eq!(summary, [
+            ("read", Some(0.0), true), // Half the bytes in the same time
+            ("parse", Some(20.0), true),
+            ("tiny", Some(400.0), false), // Under MIN_REGRESSION_SHARE
+            ("gone", None, false),
+            ("added", None, false),
+        ]);
+        assert_eq!(diff[0].bandwidth_change, Some(-0.5));
+    }
+}
@@ -0,0 +1,333 @@
+/// Count, mean, variance (Welford), min and max in a single pass.
+#[derive(Copy, Clone, Debug)]
+pub struct RunningStats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Default for RunningStats {
+    fn default() -> Self {
+        RunningStats { count: 0, min: f64::INFINITY, max: f64::NEG_INFINITY, mean: 0.0, m2: 0.0 }
+    }
+}
+
+impl RunningStats {
+    pub fn push(&mut self, x: f64) {
+        self.count += 1;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// Population variance.
+    pub fn variance(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.m2 / self.count as f64 }
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+}
+
+/// Quantiles of non-negative values in bounded memory, within a relative error of `alpha`.
+///
+/// Values go into logarithmic buckets `(gamma^(i-1), gamma^i]` with `gamma = (1 + alpha) / (1 - alpha)`,
+/// so the number of buckets only depends on the ratio between the largest and smallest value.
+pub struct QuantileSketch {
+    alpha: f64,
+    ln_gamma: f64,
+    count: u64,
+    zero_count: u64,
+    first_index: i32,
+    buckets: Vec<u64>,
+}
+
+impl QuantileSketch {
+    pub fn new(alpha: f64) -> Self {
+        let gamma = (1.0 + alpha) / (1.0 - alpha);
+        QuantileSketch { alpha, ln_gamma: gamma.ln(), count: 0, zero_count: 0, first_index: 0, buckets: Vec::new() }
+    }
+
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Negative and NaN values are counted as zero.
+    pub fn push(&mut self, x: f64) {
+        self.count += 1;
+        if x.is_nan() || x < f64::MIN_POSITIVE {
+            self.zero_count += 1;
+            return;
+        }
+
+        let index = (x.ln() / self.ln_gamma).ceil() as i32;
+        if self.buckets.is_empty() {
+            self.first_index = index;
+        } else if index < self.first_index {
+            let grow = (self.first_index - index) as usize;
+            self.buckets.splice(0..0, std::iter::repeat_n(0, grow));
+            self.first_index = index;
+        }
+
+        let offset = (index - self.first_index) as usize;
+        if offset >= self.buckets.len() {
+            self.buckets.resize(offset + 1, 0);
+        }
+        self.buckets[offset] += 1;
+    }
+
+    /// Value at quantile `q` in [0, 1], None when empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64) as u64;
+        if rank < self.zero_count {
+            return Some(0.0);
+        }
+
+        let mut seen = self.zero_count;
+        let bucket = self.buckets.iter().position(|&count| {
+            seen += count;
+            seen > rank
+        })?;
+
+        // Midpoint of the bucket in relative terms, which bounds the error by alpha
+        let index = self.first_index + bucket as i32;
+        let gamma = self.ln_gamma.exp();
+        Some(2.0 * (index as f64 * self.ln_gamma).exp() / (gamma + 1.0))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HistogramScale {
+    Linear,
+    Log,
+}
+
+impl HistogramScale {
+    pub fn name(self) -> &'static str {
+        match self {
+            HistogramScale::Linear => "linear",
+            HistogramScale::Log => "log",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HistogramScale> {
+        [HistogramScale::Linear, HistogramScale::Log].into_iter().find(|scale| scale.name() == name)
+    }
+}
+
+/// Fixed-range histogram. Values outside [lo, hi) are counted in the first or last bucket.
+pub struct Histogram {
+    pub scale: HistogramScale,
+    pub lo: f64,
+    pub hi: f64,
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Log scale needs `lo > 0`.
+    pub fn new(scale: HistogramScale, lo: f64, hi: f64, bucket_count: usize) -> Self {
+        Histogram { scale, lo, hi, counts: vec![0; bucket_count.max(1)] }
+    }
+
+    fn position(&self, x: f64) -> f64 {
+        match self.scale {
+            HistogramScale::Linear => (x - self.lo) / (self.hi - self.lo),
+            HistogramScale::Log => (x / self.lo).ln() / (self.hi / self.lo).ln(),
+        }
+    }
+
+    pub fn push(&mut self, x: f64) {
+        let last = self.counts.len() - 1;
+        let position = self.position(x) * self.counts.len() as f64;
+        // Also sends NaN, and log of values <= 0, to the first bucket
+        let bucket = if position >= 0.0 { (position as usize).min(last) } else { 0 };
+        self.counts[bucket] += 1;
+    }
+
+    /// Lower edge of bucket `index`, `index == counts.len()` gives the upper edge of the last.
+    pub fn edge(&self, index: usize) -> f64 {
+        let t = index as f64 / self.counts.len() as f64;
+        match self.scale {
+            HistogramScale::Linear => self.lo + (self.hi - self.lo) * t,
+            HistogramScale::Log => self.lo * (self.hi / self.lo).powf(t),
+        }
+    }
+}
+
+/// Histogram of integers in log-scale buckets, 8 per power of two, so quantiles come within about
+/// 6% (exact below 8). Bucket lookup is a few instructions, cheap enough to run for every profiled block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogHistogram {
+    pub counts: Vec<u64>,
+    pub max: u64,
+}
+
+impl Default for LogHistogram {
+    fn default() -> Self {
+        LogHistogram { counts: vec![0; LogHistogram::BUCKET_COUNT], max: 0 }
+    }
+}
+
+impl LogHistogram {
+    const SUB_BUCKET_BITS: u32 = 3;
+    const SUB_BUCKETS: u64 = 1 << LogHistogram::SUB_BUCKET_BITS;
+    pub const BUCKET_COUNT: usize = (u64::BITS - LogHistogram::SUB_BUCKET_BITS + 1) as usize * LogHistogram::SUB_BUCKETS as usize;
+
+    #[inline]
+    pub fn bucket(value: u64) -> usize {
+        if value < LogHistogram::SUB_BUCKETS {
+            return value as usize;
+        }
+        let exponent = u64::BITS - 1 - value.leading_zeros();
+        let shift = exponent - LogHistogram::SUB_BUCKET_BITS;
+        let sub_bucket = (value >> shift) & (LogHistogram::SUB_BUCKETS - 1);
+        ((shift + 1) as u64 * LogHistogram::SUB_BUCKETS + sub_bucket) as usize
+    }
+
+    /// Smallest and largest value in `bucket`.
+    pub fn bucket_range(bucket: usize) -> (u64, u64) {
+        let bucket = bucket as u64;
+        if bucket < LogHistogram::SUB_BUCKETS {
+            return (bucket, bucket);
+        }
+        let shift = bucket / LogHistogram::SUB_BUCKETS - 1;
+        let lo = (LogHistogram::SUB_BUCKETS + bucket % LogHistogram::SUB_BUCKETS) << shift;
+        (lo, lo + ((1 << shift) - 1))
+    }
+
+    pub fn push(&mut self, value: u64) {
+        self.counts[LogHistogram::bucket(value)] += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn merge(&mut self, other: &LogHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    /// Value at quantile `q` in [0, 1], as the middle of its bucket. None when empty.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        if q >= 1.0 {
+            return Some(self.max);
+        }
+
+        let rank = (q.max(0.0) * (count - 1) as f64) as u64;
+        let mut seen = 0;
+        let bucket = self.counts.iter().position(|&count| {
+            seen += count;
+            seen > rank
+        })?;
+        let (lo, hi) = LogHistogram::bucket_range(bucket);
+        Some((lo + (hi - lo) / 2).min(self.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_stats() {
+        let mut stats = RunningStats::default();
+        for x in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.push(x);
+        }
+        assert_eq!((stats.count, stats.min, stats.max), (8, 2.0, 9.0));
+        assert!((stats.mean() - 5.0).abs() < 1e-12);
+        assert!((stats.std_dev() - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sketch_quantiles_are_within_alpha() {
+        let alpha = 0.001;
+        let mut sketch = QuantileSketch::new(alpha);
+        // Pushed out of order, so buckets grow at both ends
+        let values = (1..=10000).map(|i| ((i * 7919) % 10000 + 1) as f64 * 0.37).collect::<Vec<_>>();
+        for &x in &values {
+            sketch.push(x);
+        }
+        sketch.push(0.0);
+
+        let mut sorted = values.clone();
+        sorted.push(0.0);
+        sorted.sort_by(f64::total_cmp);
+
+        for q in [0.0, 0.01, 0.25, 0.5, 0.75, 0.99, 1.0] {
+            let exact = sorted[(q * (sorted.len() - 1) as f64) as usize];
+            let estimate = sketch.quantile(q).unwrap();
+            assert!((estimate - exact).abs() <= alpha * exact, "q {q}: {estimate} vs {exact}");
+        }
+        assert_eq!(sketch.count(), 10001);
+        assert!(QuantileSketch::new(alpha).quantile(0.5).is_none());
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut linear = Histogram::new(HistogramScale::Linear, 0.0, 10.0, 5);
+        for x in [-1.0, 0.0, 1.9, 2.0, 9.99, 10.0, 50.0] {
+            linear.push(x);
+        }
+        assert_eq!(linear.counts, [3, 1, 0, 0, 3]);
+        assert_eq!(linear.edge(1), 2.0);
+
+        let mut log = Histogram::new(HistogramScale::Log, 1.0, 1000.0, 3);
+        for x in [0.0, 5.0, 10.0, 500.0] {
+            log.push(x);
+        }
+        assert_eq!(log.counts, [2, 1, 1]);
+        assert!((log.edge(2) - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn log_histogram() {
+        for value in [0, 7, 8, 9, 15, 16, 17, 1000, 123456789, u64::MAX] {
+            let bucket = LogHistogram::bucket(value);
+            let (lo, hi) = LogHistogram::bucket_range(bucket);
+            assert!(lo <= value && value <= hi, "{value} in bucket {bucket} [{lo}, {hi}]");
+            assert!(hi - lo <= lo / 8, "{value}");
+        }
+        assert_eq!(LogHistogram::bucket(u64::MAX), LogHistogram::BUCKET_COUNT - 1);
+
+        let mut histogram = LogHistogram::default();
+        for value in 1..=1000 {
+            histogram.push(value);
+        }
+        let mut other = LogHistogram::default();
+        other.push(5000);
+        histogram.merge(&other);
+
+        assert_eq!(histogram.count(), 1001);
+        let p50 = histogram.quantile(0.5).unwrap() as f64;
+        assert!((p50 - 500.0).abs() <= 500.0 * 0.07, "{p50}");
+        assert_eq!(histogram.quantile(1.0), Some(5000));
+        assert!(LogHistogram::default().quantile(0.5).is_none());
+    }
+}
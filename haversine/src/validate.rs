@@ -0,0 +1,211 @@
+use haversine::answer::AnswerFile;
+use haversine::compare::{ulp_distance, Tolerance, WorstList};
+use haversine::json::{object, Value};
+use haversine::math::MathBackend;
+use haversine::{time_function, Pair};
+
+pub struct Mismatch {
+    pub index: usize,
+    pub pair: Pair,
+    pub distance: f64,
+    pub reference: f64,
+    pub ulps: u64,
+}
+
+pub struct ValidationReport {
+    pub tolerance: Tolerance,
+    pub pair_count: usize,
+    pub answer_count: usize,
+    pub mismatch_count: usize,
+    pub max_abs_error: f64,
+    pub max_ulps: u64,
+    pub sum: f64,
+    pub reference_sum: f64,
+    pub worst: WorstList<Mismatch>,
+}
+
+impl ValidationReport {
+    pub fn passed(&self) -> bool {
+        self.pair_count == self.answer_count
+            && self.mismatch_count == 0
+            && self.tolerance.matches(self.sum, self.reference_sum)
+    }
+
+    pub fn print(&self) {
+        println!("Reference sum: {:.16}", self.reference_sum);
+        println!("Difference: {:.16}", self.sum - self.reference_sum);
+
+        if self.pair_count != self.answer_count {
+            println!("Pair count mismatch: {} pairs, {} answers", self.pair_count, self.answer_count);
+        }
+
+        let Tolerance { abs, ulps } = self.tolerance;
+        println!("Pairs checked: {}", self.pair_count.min(self.answer_count));
+        println!("Mismatches: {} (tolerance {abs:e} or {ulps} ULP)", self.mismatch_count);
+        println!("Max error: {:e} ({} ULP)", self.max_abs_error, self.max_ulps);
+
+        if self.mismatch_count > 0 {
+            println!("Worst pairs:");
+            for (error, mismatch) in self.worst.iter() {
+                let Mismatch { index, pair: Pair { x0, y0, x1, y1 }, distance, reference, ulps } = mismatch;
+                println!("  [{index}] ({x0:.16}, {y0:.16}) -> ({x1:.16}, {y1:.16}): \
+                          {distance:.16} vs {reference:.16} (error {error:e}, {ulps} ULP)");
+            }
+        }
+
+        println!("{}", if self.passed() { "PASSED" } else { "FAILED" });
+    }
+
+    pub fn to_json(&self) -> Value {
+        let worst = self.worst.iter().map(|(error, mismatch)| {
+            let Mismatch { index, pair: Pair { x0, y0, x1, y1 }, distance, reference, ulps } = *mismatch;
+            object([
+                ("index", index.into()),
+                ("x0", x0.into()),
+                ("y0", y0.into()),
+                ("x1", x1.into()),
+                ("y1", y1.into()),
+                ("distance", distance.into()),
+                ("reference", reference.into()),
+                ("error", (*error).into()),
+                ("ulps", ulps.into()),
+            ])
+        });
+
+        object([
+            ("passed", self.passed().into()),
+            ("reference_sum", self.reference_sum.into()),
+            ("difference", (self.sum - self.reference_sum).into()),
+            ("pair_count", self.pair_count.into()),
+            ("answer_count", self.answer_count.into()),
+            ("tolerance_abs", self.tolerance.abs.into()),
+            ("tolerance_ulps", self.tolerance.ulps.into()),
+            ("mismatches", self.mismatch_count.into()),
+            ("max_abs_error", self.max_abs_error.into()),
+            ("max_ulps", self.max_ulps.into()),
+            ("worst", Value::Array(worst.collect())),
+        ])
+    }
+}
+
+/// Compare every pair's distance against the answers, keeping the `worst_count` largest errors, and
+/// `sum`, the average the caller computed, against the answers' sum.
+pub fn validate_pairs(pairs: &[Pair], backend: MathBackend, earth_radius: f64, sum: f64, answers: &AnswerFile, tolerance: Tolerance, worst_count: usize) -> ValidationReport {
+    time_function!();
+
+    let mut report = ValidationReport {
+        tolerance,
+        pair_count: pairs.len(),
+        answer_count: answers.distances.len(),
+        mismatch_count: 0,
+        max_abs_error: 0.0,
+        max_ulps: 0,
+        sum,
+        reference_sum: answers.sum,
+        worst: WorstList::new(worst_count),
+    };
+
+    for (index, (pair, &reference)) in pairs.iter().zip(&answers.distances).enumerate() {
+        let distance = backend.distance(pair, earth_radius);
+
+        let error = (distance - reference).abs();
+        let ulps = ulp_distance(distance, reference);
+        // f64::max would drop a NaN error and report the run as close
+        if error.is_nan() || error > report.max_abs_error {
+            report.max_abs_error = error;
+        }
+        report.max_ulps = report.max_ulps.max(ulps);
+
+        if !tolerance.matches(distance, reference) {
+            report.mismatch_count += 1;
+            report.worst.push(error, Mismatch { index, pair: *pair, distance, reference, ulps });
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EARTH_RADIUS: f64 = 6372.8;
+
+    fn pairs() -> Vec<Pair> {
+        (0..8).map(|i| Pair { x0: i as f64 * 10.0, y0: 5.0, x1: -(i as f64) * 7.0, y1: -20.0 }).collect()
+    }
+
+    fn answers_for(pairs: &[Pair]) -> AnswerFile {
+        let backend = MathBackend::Reference;
+        let distances = pairs.iter().map(|pair| backend.distance(pair, EARTH_RADIUS)).collect();
+        AnswerFile::new(distances, backend.sum_distances(pairs, EARTH_RADIUS), EARTH_RADIUS)
+    }
+
+    fn validate(pairs: &[Pair], sum: f64, answers: &AnswerFile, worst_count: usize) -> ValidationReport {
+        validate_pairs(pairs, MathBackend::Reference, EARTH_RADIUS, sum, answers, Tolerance::default(), worst_count)
+    }
+
+    #[test]
+    fn matching_answers_pass() {
+        let pairs = pairs();
+        let answers = answers_for(&pairs);
+        let report = validate(&pairs, answers.sum, &answers, 4);
+
+        assert_eq!(report.mismatch_count, 0);
+        assert_eq!(report.max_abs_error, 0.0);
+        assert_eq!(report.max_ulps, 0);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn mismatches_are_counted_worst_first() {
+        let pairs = pairs();
+        let mut answers = answers_for(&pairs);
+        answers.distances[1] += 1.0;
+        answers.distances[3] += 100.0;
+        answers.distances[6] += 10.0;
+        // Within tolerance, so not a mismatch
+        answers.distances[7] = f64::from_bits(answers.distances[7].to_bits() + 1);
+
+        let report = validate(&pairs, answers.sum, &answers, 2);
+        assert_eq!(report.mismatch_count, 3);
+        assert!((report.max_abs_error - 100.0).abs() < 1e-9);
+        assert_eq!(report.worst.iter().map(|(_, mismatch)| mismatch.index).collect::<Vec<_>>(), [3, 6]);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn nan_distances_are_the_max_error() {
+        let pairs = pairs();
+        let mut answers = answers_for(&pairs);
+        answers.distances[2] = f64::NAN;
+
+        let report = validate(&pairs, answers.sum, &answers, 4);
+        assert!(report.max_abs_error.is_nan());
+        assert_eq!(report.max_ulps, u64::MAX);
+        assert_eq!(report.mismatch_count, 1);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn pair_count_must_match_answer_count() {
+        let pairs = pairs();
+        let answers = answers_for(&pairs[..5]);
+        let report = validate(&pairs, answers.sum, &answers, 4);
+
+        assert_eq!((report.pair_count, report.answer_count), (8, 5));
+        // Only the pairs with answers are compared
+        assert_eq!(report.mismatch_count, 0);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn sum_must_match_reference_sum() {
+        let pairs = pairs();
+        let answers = answers_for(&pairs);
+        let report = validate(&pairs, answers.sum + 1e-3, &answers, 4);
+
+        assert_eq!(report.mismatch_count, 0);
+        assert!(!report.passed());
+    }
+}
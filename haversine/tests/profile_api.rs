@@ -0,0 +1,75 @@
+// Builds against whichever of profile.rs and profile_stub.rs the features pick, so running it with
+// and without `--features profile` checks that both give the same API.
+
+use std::alloc::System;
+use std::thread;
+
+use haversine::profile::{self, Anchor, ProfilingAllocator, TimeBlock};
+use haversine::{function_name, time_bandwidth, time_block, time_function};
+
+#[global_allocator]
+static ALLOCATOR: ProfilingAllocator = ProfilingAllocator(System);
+
+#[derive(Copy, Clone)]
+enum Stage {
+    Read,
+    Parse,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "api_read",
+            Stage::Parse => "api_parse",
+        }
+    }
+}
+
+fn profiled_work() -> usize {
+    time_function!();
+    let data = vec![1u8; 4096];
+    {
+        // Labels and byte counts are expressions, not literals
+        time_bandwidth!(Stage::Read.name(), data.len());
+        std::hint::black_box(&data);
+    }
+    for _ in 0..3 {
+        time_block!(Stage::Parse.name());
+    }
+    data.len()
+}
+
+#[test]
+fn api_matches_across_configurations() {
+    profile::track_page_faults(true);
+    profile::track_durations(true);
+    profile::track_allocations(true);
+    let _ = profile::track_perf_counters(false);
+    profile::start_trace(1024);
+    assert_eq!(thread::spawn(profiled_work).join().unwrap(), 4096);
+
+    {
+        // What the macros expand to
+        static ANCHOR: Anchor = Anchor::new();
+        let _block = TimeBlock::new(function_name!(), ANCHOR.index(), 0);
+    }
+
+    let overhead = profile::calibrate_overhead();
+    let mut tree = profile::call_tree();
+    profile::subtract_overhead(&mut tree, overhead);
+    let _ = profile::overhead_ticks(&tree, overhead);
+    let _ = (profile::samples(), profile::thread_traces(), profile::MAX_ANCHORS, profile::MAX_CALL_PATHS);
+    profile::print_time_records(1, 1);
+
+    let labels = profile::time_records().iter().map(|record| record.label).collect::<Vec<_>>();
+    if cfg!(feature = "profile") {
+        for label in ["profiled_work", "api_read", "api_parse", "api_matches_across_configurations"] {
+            assert!(labels.contains(&label), "{label} missing from {labels:?}");
+        }
+        let parse = profile::time_records().into_iter().find(|record| record.label == "api_parse").unwrap();
+        assert_eq!(parse.hit_count, 3);
+    } else {
+        assert!(labels.is_empty());
+        assert!(profile::thread_time_records().is_empty());
+    }
+}
@@ -1,7 +1,10 @@
 mod rand;
 
 use std::{env, fs};
-use std::mem::size_of;
+
+use haversine::answer::{AnswerFile, Distribution};
+use haversine::format::Format;
+use haversine::Pair;
 
 fn print_usage() {
     use std::path::Path;
@@ -13,12 +16,13 @@ fn print_usage() {
             .unwrap().to_string();
 
     println!("Usage: {exe_name} [uniform/cluster] [random seed] [number of coordinate pairs to generate]");
+    println!("       {exe_name} [uniform/cluster] [random seed] [number of coordinate pairs to generate] [json/csv/ndjson]");
 }
 
 fn main() -> std::io::Result<()> {
     let args = env::args().collect::<Vec<String>>();
     
-    if args.len() != 4 {
+    if !(4..=5).contains(&args.len()) {
         print_usage();
         return Ok(());
     }
@@ -32,13 +36,17 @@ fn main() -> std::io::Result<()> {
     let mut x_radius = max_allowed_x;
     let mut y_radius = max_allowed_y;
 
-    let distribution = args[1].as_str();
-    
-    if distribution == "cluster" {
-        cluster_count_left = 0;
-    } else if distribution != "uniform" {
-        println!("WARNING: Unrecognized method name. Using 'uniform'.");
-    }
+    let distribution = match args[1].as_str() {
+        "cluster" => {
+            cluster_count_left = 0;
+            Distribution::Cluster
+        }
+        "uniform" => Distribution::Uniform,
+        _ => {
+            println!("WARNING: Unrecognized method name. Using 'uniform'.");
+            Distribution::Uniform
+        }
+    };
     
     let random_seed = if let Ok(parsed_value) = args[2].parse::<u64>() {
         parsed_value
@@ -56,6 +64,16 @@ fn main() -> std::io::Result<()> {
         return Ok(());
     };
     
+    let format = match args.get(4) {
+        Some(name) => if let Some(format) = Format::from_name(name) {
+            format
+        } else {
+            print_usage();
+            return Ok(());
+        },
+        None => Format::Json,
+    };
+    
     let max_pairs_exp = 34;
     let max_pairs = 1usize << max_pairs_exp;
     if num_pairs > max_pairs {
@@ -68,8 +86,9 @@ fn main() -> std::io::Result<()> {
     let cluster_count_max = 1 + (num_pairs as u64 / 64);
     
     let mut data_str = String::with_capacity(15 + num_pairs*100);
-    data_str += "{\"pairs\": [\n";
-    let mut answers = Vec::<u8>::with_capacity((num_pairs+1) * size_of::<f64>());
+    format.write_begin(&mut data_str);
+    let mut distances = Vec::<f64>::with_capacity(num_pairs);
+    let earth_radius = 6372.8;
     
     for i in 0..num_pairs {
         if cluster_count_left == 0 {
@@ -87,23 +106,24 @@ fn main() -> std::io::Result<()> {
         let x1 = random_series.random_degree(x_center, x_radius, max_allowed_x);
         let y1 = random_series.random_degree(y_center, y_radius, max_allowed_y);
 
-        let earth_radius = 6372.8;
         let haversine_distance = haversine::reference_haversine(x0, y0, x1, y1, earth_radius);
 
         sum += sum_coef * haversine_distance;
 
-        let json_sep = if i == (num_pairs - 1) { "\n" } else { ",\n" };
-        data_str += format!("    {{\"x0\":{x0:.16}, \"y0\":{y0:.16}, \"x1\":{x1:.16}, \"y1\":{y1:.16}}}{json_sep}").as_str();
-        answers.extend_from_slice(&haversine_distance.to_be_bytes());
+        format.write_pair(&mut data_str, &Pair { x0, y0, x1, y1 }, i == (num_pairs - 1));
+        distances.push(haversine_distance);
     }
     
-    data_str += "]}\n";
-    answers.extend_from_slice(&sum.to_be_bytes());
+    format.write_end(&mut data_str);
+    let mut answers = AnswerFile::new(distances, sum, earth_radius);
+    answers.seed = Some(random_seed);
+    answers.distribution = distribution;
     
-    fs::write(format!("data_{num_pairs}_flex.json"), data_str)?;
-    fs::write(format!("data_{num_pairs}_haveranswer.f64"), answers)?;
+    fs::write(format!("data_{num_pairs}_flex.{}", format.extension()), data_str)?;
+    answers.write(format!("data_{num_pairs}_haveranswer.f64"))?;
     
-    println!("Distribution: {distribution}");
+    println!("Distribution: {}", distribution.name());
+    println!("Format: {}", format.name());
     println!("Random seed: {random_seed}");
     println!("Pair count: {num_pairs}");
     println!("Expected sum: {sum:.16}");
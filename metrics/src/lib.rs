@@ -1,3 +1,6 @@
 pub mod timing;
 pub mod repetition_tester;
 pub mod memory;
+pub mod perf;
+pub mod sampling;
+pub mod output;
@@ -1,12 +1,18 @@
+#[cfg(windows)]
 use std::sync::OnceLock;
+#[cfg(windows)]
 use windows_sys::Win32::Foundation::{FALSE, HANDLE};
+#[cfg(windows)]
 use windows_sys::Win32::System::Threading::{GetCurrentProcessId, OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ};
+#[cfg(windows)]
 use windows_sys::Win32::System::ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
 
+#[cfg(windows)]
 struct OSMetrics {
     process_handle: HANDLE,
 }
 
+#[cfg(windows)]
 impl OSMetrics {
     pub fn get_global_metrics() -> &'static OSMetrics {
         static GLOBAL_METRICS: OnceLock<OSMetrics> = OnceLock::new();
@@ -18,6 +24,7 @@ impl OSMetrics {
     }
 }
 
+#[cfg(windows)]
 pub fn read_os_page_fault_count() -> u64 {
     let mut memory_counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
     memory_counters.cb = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
@@ -29,4 +36,14 @@ pub fn read_os_page_fault_count() -> u64 {
     }
 
     memory_counters.PageFaultCount as u64
-}
\ No newline at end of file
+}
+
+/// Minor and major faults, to match the Windows count, which includes soft faults.
+#[cfg(unix)]
+pub fn read_os_page_fault_count() -> u64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+    }
+    usage.ru_minflt as u64 + usage.ru_majflt as u64
+}
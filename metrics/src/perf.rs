@@ -0,0 +1,414 @@
+use std::io;
+
+/// Counters `PerfCounters` can open. Hardware events need a PMU, which VMs often don't expose.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PerfEvent {
+    Cycles,
+    Instructions,
+    BranchMisses,
+    CacheMisses,
+    TaskClock, // Nanoseconds on the CPU
+    ContextSwitches,
+}
+
+impl PerfEvent {
+    pub const COUNT: usize = 6;
+    pub const ALL: [PerfEvent; PerfEvent::COUNT] = [
+        PerfEvent::Cycles,
+        PerfEvent::Instructions,
+        PerfEvent::BranchMisses,
+        PerfEvent::CacheMisses,
+        PerfEvent::TaskClock,
+        PerfEvent::ContextSwitches,
+    ];
+    pub const HARDWARE: [PerfEvent; 4] = [PerfEvent::Cycles, PerfEvent::Instructions, PerfEvent::BranchMisses, PerfEvent::CacheMisses];
+    pub const SOFTWARE: [PerfEvent; 2] = [PerfEvent::TaskClock, PerfEvent::ContextSwitches];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PerfEvent::Cycles => "cycles",
+            PerfEvent::Instructions => "instructions",
+            PerfEvent::BranchMisses => "branch_misses",
+            PerfEvent::CacheMisses => "cache_misses",
+            PerfEvent::TaskClock => "task_clock",
+            PerfEvent::ContextSwitches => "context_switches",
+        }
+    }
+}
+
+/// A value for each event that was counted.
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub struct PerfCounts {
+    values: [u64; PerfEvent::COUNT],
+    present: u8, // Bit per event
+}
+
+impl PerfCounts {
+    pub fn get(&self, event: PerfEvent) -> Option<u64> {
+        (self.present & (1 << event as u8) != 0).then_some(self.values[event as usize])
+    }
+
+    pub fn set(&mut self, event: PerfEvent, value: u64) {
+        self.values[event as usize] = value;
+        self.present |= 1 << event as u8;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.present == 0
+    }
+
+    /// Counted events and their values, in `PerfEvent::ALL` order.
+    pub fn iter(&self) -> impl Iterator<Item = (PerfEvent, u64)> + '_ {
+        PerfEvent::ALL.into_iter().filter_map(|event| Some((event, self.get(event)?)))
+    }
+
+    /// Change since `earlier`, for the events counted in both.
+    pub fn since(&self, earlier: &PerfCounts) -> PerfCounts {
+        let mut delta = PerfCounts::default();
+        for (event, value) in self.iter() {
+            if let Some(earlier) = earlier.get(event) {
+                delta.set(event, value.wrapping_sub(earlier));
+            }
+        }
+        delta
+    }
+
+    /// Adds the events of `other`, keeping events only one side has.
+    pub fn add(&mut self, other: &PerfCounts) {
+        for (event, value) in other.iter() {
+            let sum = self.get(event).unwrap_or(0).wrapping_add(value);
+            self.set(event, sum);
+        }
+    }
+
+    /// Instructions per cycle, when both were counted.
+    pub fn ipc(&self) -> Option<f64> {
+        let cycles = self.get(PerfEvent::Cycles)?;
+        let instructions = self.get(PerfEvent::Instructions)?;
+        (cycles != 0).then(|| instructions as f64 / cycles as f64)
+    }
+}
+
+/// A group of counters for the calling thread, read together in one system call. Hardware events
+/// count user mode only; software events include the kernel work done for the thread where
+/// perf_event_paranoid allows it.
+/// Values are scaled up when the kernel had to multiplex the group with other counters.
+pub struct PerfCounters {
+    events: Vec<PerfEvent>,
+    #[cfg(target_os = "linux")]
+    fds: Vec<std::os::fd::OwnedFd>, // Group leader first
+}
+
+impl PerfCounters {
+    /// Opens as many hardware events as the PMU gives, or the software events when it gives none.
+    pub fn open() -> io::Result<PerfCounters> {
+        PerfCounters::open_events(&PerfEvent::HARDWARE).or_else(|_| PerfCounters::open_events(&PerfEvent::SOFTWARE))
+    }
+
+    /// Opens the events of `events` that are available, failing only if none of them is.
+    pub fn open_events(events: &[PerfEvent]) -> io::Result<PerfCounters> {
+        sys::open(events)
+    }
+
+    pub fn events(&self) -> &[PerfEvent] {
+        &self.events
+    }
+
+    pub fn read(&self) -> io::Result<PerfCounts> {
+        sys::read(self)
+    }
+}
+
+/// The kernel's TSC to nanoseconds scale as `(mult, shift)`, where `ns = ticks * mult >> shift`.
+/// Only published while the TSC is the clock source, through the first page of a perf event mapping.
+pub fn tsc_conversion() -> io::Result<(u32, u16)> {
+    sys::tsc_conversion()
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::ptr;
+    use std::sync::atomic::{fence, Ordering};
+
+    use super::{PerfCounters, PerfCounts, PerfEvent};
+
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_TYPE_SOFTWARE: u32 = 1;
+
+    const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+    const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+    const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+    const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+    const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+    const PERF_COUNT_SW_DUMMY: u64 = 9;
+
+    const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+    const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+    const PERF_FORMAT_GROUP: u64 = 1 << 3;
+
+    const ATTR_DISABLED: u64 = 1 << 0;
+    const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+    const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+
+    const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+    const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+    const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+    const PERF_IOC_FLAG_GROUP: libc::c_ulong = 1;
+
+    /// `struct perf_event_attr` up to PERF_ATTR_SIZE_VER5.
+    #[repr(C)]
+    #[derive(Default)]
+    struct PerfEventAttr {
+        kind: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+        config2: u64,
+        branch_sample_type: u64,
+        sample_regs_user: u64,
+        sample_stack_user: u32,
+        clockid: i32,
+        sample_regs_intr: u64,
+        aux_watermark: u32,
+        sample_max_stack: u16,
+        reserved: u16,
+    }
+
+    fn event_config(event: PerfEvent) -> (u32, u64) {
+        match event {
+            PerfEvent::Cycles => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+            PerfEvent::Instructions => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+            PerfEvent::BranchMisses => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_BRANCH_MISSES),
+            PerfEvent::CacheMisses => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_MISSES),
+            PerfEvent::TaskClock => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_TASK_CLOCK),
+            PerfEvent::ContextSwitches => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CONTEXT_SWITCHES),
+        }
+    }
+
+    fn open_event(event: PerfEvent, leader: Option<&OwnedFd>) -> io::Result<OwnedFd> {
+        let (kind, config) = event_config(event);
+        let mut attr = PerfEventAttr {
+            kind,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            read_format: PERF_FORMAT_GROUP | PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            // The leader starts disabled so the whole group is enabled at once. Hardware events count
+            // user mode only, which is all an unprivileged process may count at the default
+            // perf_event_paranoid level.
+            flags: if leader.is_none() { ATTR_DISABLED } else { 0 } | ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV,
+            ..PerfEventAttr::default()
+        };
+        let group_fd = leader.map_or(-1, |leader| leader.as_raw_fd());
+        if kind != PERF_TYPE_SOFTWARE {
+            return event_open(&attr, group_fd);
+        }
+
+        // Software events happen in the kernel on the thread's behalf, and context switches only
+        // ever in kernel mode, so excluding it would leave them at 0. Where that isn't allowed, the
+        // task clock still counts user time, but context switches are better missing than zero.
+        attr.flags &= !ATTR_EXCLUDE_KERNEL;
+        match event_open(&attr, group_fd) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && event != PerfEvent::ContextSwitches => {
+                attr.flags |= ATTR_EXCLUDE_KERNEL;
+                event_open(&attr, group_fd)
+            }
+            result => result,
+        }
+    }
+
+    fn event_open(attr: &PerfEventAttr, group_fd: i32) -> io::Result<OwnedFd> {
+        // pid 0 and cpu -1: the calling thread on any CPU
+        let fd = unsafe { libc::syscall(libc::SYS_perf_event_open, attr as *const PerfEventAttr, 0, -1, group_fd, PERF_FLAG_FD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+    }
+
+    /// The start of `struct perf_event_mmap_page`, up to the time fields.
+    #[repr(C)]
+    struct MmapPage {
+        _version: [u32; 2],
+        lock: u32,
+        _index: u32,
+        _offset_and_times: [u64; 3],
+        capabilities: u64,
+        _pmc_width: u16,
+        time_shift: u16,
+        time_mult: u32,
+    }
+
+    const CAP_USER_TIME: u64 = 1 << 3;
+
+    pub fn tsc_conversion() -> io::Result<(u32, u16)> {
+        // A dummy event counts nothing, it is only opened for its mapping. It has to be enabled, as the
+        // kernel fills in the time fields when it schedules the event in.
+        let attr = PerfEventAttr {
+            kind: PERF_TYPE_SOFTWARE,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config: PERF_COUNT_SW_DUMMY,
+            flags: ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV,
+            ..PerfEventAttr::default()
+        };
+        let fd = event_open(&attr, -1)?;
+
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page = unsafe { libc::mmap(ptr::null_mut(), size, libc::PROT_READ, libc::MAP_SHARED, fd.as_raw_fd(), 0) };
+        if page == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let page = page as *const MmapPage;
+
+        // The kernel bumps the lock around updates, so retry until a read sees the same value twice
+        let conversion = loop {
+            unsafe {
+                let lock = ptr::read_volatile(&(*page).lock);
+                fence(Ordering::Acquire);
+                let capabilities = ptr::read_volatile(&(*page).capabilities);
+                let mult = ptr::read_volatile(&(*page).time_mult);
+                let shift = ptr::read_volatile(&(*page).time_shift);
+                fence(Ordering::Acquire);
+                if ptr::read_volatile(&(*page).lock) == lock {
+                    break (capabilities & CAP_USER_TIME != 0 && mult != 0).then_some((mult, shift));
+                }
+            }
+        };
+        unsafe { libc::munmap(page as *mut libc::c_void, size) };
+
+        conversion.ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the kernel does not publish a TSC conversion"))
+    }
+
+    pub fn open(events: &[PerfEvent]) -> io::Result<PerfCounters> {
+        let mut counters = PerfCounters { events: Vec::new(), fds: Vec::new() };
+        let mut first_error = None;
+        for &event in events {
+            match open_event(event, counters.fds.first()) {
+                Ok(fd) => {
+                    counters.events.push(event);
+                    counters.fds.push(fd);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        let Some(leader) = counters.fds.first() else {
+            return Err(first_error.unwrap_or(io::ErrorKind::InvalidInput.into()));
+        };
+        for request in [PERF_EVENT_IOC_RESET, PERF_EVENT_IOC_ENABLE] {
+            if unsafe { libc::ioctl(leader.as_raw_fd(), request as _, PERF_IOC_FLAG_GROUP) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(counters)
+    }
+
+    pub fn read(counters: &PerfCounters) -> io::Result<PerfCounts> {
+        // nr, time enabled, time running, then a value per event in group order
+        let mut buffer = [0u64; 3 + PerfEvent::COUNT];
+        let size = (3 + counters.events.len()) * std::mem::size_of::<u64>();
+        let read = unsafe { libc::read(counters.fds[0].as_raw_fd(), buffer.as_mut_ptr().cast(), size) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if read as usize != size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let [_, enabled, running, ref values @ ..] = buffer;
+        let mut counts = PerfCounts::default();
+        for (&event, &value) in counters.events.iter().zip(values) {
+            let scaled = if running == 0 || running == enabled { value } else { (value as u128 * enabled as u128 / running as u128) as u64 };
+            counts.set(event, scaled);
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    use super::{PerfCounters, PerfCounts, PerfEvent};
+
+    pub fn open(_: &[PerfEvent]) -> io::Result<PerfCounters> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "perf_event_open is Linux only"))
+    }
+
+    pub fn read(_: &PerfCounters) -> io::Result<PerfCounts> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "perf_event_open is Linux only"))
+    }
+
+    pub fn tsc_conversion() -> io::Result<(u32, u16)> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "perf_event_open is Linux only"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_arithmetic() {
+        let mut earlier = PerfCounts::default();
+        earlier.set(PerfEvent::Cycles, 100);
+        earlier.set(PerfEvent::Instructions, 50);
+        let mut later = earlier;
+        later.set(PerfEvent::Cycles, 300);
+        later.set(PerfEvent::Instructions, 450);
+        later.set(PerfEvent::CacheMisses, 7);
+
+        let delta = later.since(&earlier);
+        assert_eq!(delta.iter().collect::<Vec<_>>(), [(PerfEvent::Cycles, 200), (PerfEvent::Instructions, 400)]);
+        assert_eq!(delta.ipc(), Some(2.0));
+
+        let mut sum = delta;
+        sum.add(&later);
+        assert_eq!((sum.get(PerfEvent::Cycles), sum.get(PerfEvent::CacheMisses)), (Some(500), Some(7)));
+        assert!(PerfCounts::default().is_empty());
+    }
+
+    #[test]
+    fn counters_count_or_fail_cleanly() {
+        // Depends on the machine, so only check that whatever opens also counts
+        let Ok(counters) = PerfCounters::open() else { return };
+        assert!(!counters.events().is_empty());
+
+        let before = counters.read().unwrap();
+        let mut x = 0u64;
+        for i in 0..1_000_000 {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(i));
+        }
+        let delta = counters.read().unwrap().since(&before);
+
+        assert_eq!(delta.iter().count(), counters.events().len());
+        if let Some(instructions) = delta.get(PerfEvent::Instructions) {
+            assert!(instructions >= 1_000_000);
+        }
+        if let Some(task_clock) = delta.get(PerfEvent::TaskClock) {
+            assert!(task_clock > 0);
+        }
+    }
+
+    #[test]
+    fn context_switches_are_counted() {
+        let Ok(counters) = PerfCounters::open_events(&[PerfEvent::ContextSwitches]) else { return };
+        let before = counters.read().unwrap();
+        // Sleeping gives up the CPU, which is a switch every time
+        for _ in 0..3 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let switches = counters.read().unwrap().since(&before).get(PerfEvent::ContextSwitches);
+        assert!(switches >= Some(3), "{switches:?}");
+    }
+}
@@ -3,8 +3,9 @@ use std::io::Write;
 use std::num::NonZeroU64;
 use std::ops::{Add, AddAssign, Div};
 
-use crate::timing::{cpu_time_to_seconds, read_cpu_timer};
+use crate::timing::{cpu_time_to_seconds, read_cpu_timer, TimerPair};
 use crate::memory::read_os_page_fault_count;
+use crate::output::{csv_field, write_json_string, OutputFormat};
 
 #[derive(Clone, Copy, PartialEq)]
 enum TestMode {
@@ -40,30 +41,75 @@ impl RepetitionTestValue {
     }
     
     pub fn print(&self, label: &str, cpu_freq: u64) {
+        let mut out = io::stdout().lock();
+        self.write(&mut out, label, cpu_freq, OutputFormat::Human).unwrap();
+        out.flush().unwrap();
+    }
+
+    /// Writes the average per test. Human output has no line break, so callers can add to the
+    /// line; CSV is one `CSV_HEADER` row and JSON one object, each with a line break.
+    pub fn write(&self, out: &mut impl Write, label: &str, cpu_freq: u64, format: OutputFormat) -> io::Result<()> {
+        const GIGABYTE: f64 = 1024.0 * 1024.0 * 1024.0;
         let avg = *self / self.num_tests.max(1);
+        let seconds = NonZeroU64::new(cpu_freq).map(|freq| cpu_time_to_seconds(avg.time, freq));
+        let bandwidth = seconds.filter(|_| avg.bytes > 0).map(|seconds| avg.bytes as f64 / (seconds * GIGABYTE));
+        let kb_per_fault = (avg.page_faults > 0).then(|| avg.bytes as f64 / (avg.page_faults as f64 * 1024.0));
 
-        // Print cpu time
-        print!("{label}: {}", avg.time);
-        
-        // Print real time and bandwidth
-        if let Some(freq) = NonZeroU64::new(cpu_freq) {
-            let time_seconds = cpu_time_to_seconds(avg.time, freq);
-            let time_ms = time_seconds * 1000.0;
-            print!(" ({time_ms:.6}ms)");
-            if avg.bytes > 0 {
-                const GIGABYTE: f64 = 1024.0 * 1024.0 * 1024.0;
-                let bandwidth = avg.bytes as f64 / (time_seconds * GIGABYTE);
-                print!(" {bandwidth:.6} GB/s");
+        match format {
+            OutputFormat::Human => {
+                // Print cpu time
+                write!(out, "{label}: {}", avg.time)?;
+
+                // Print real time and bandwidth
+                if let Some(seconds) = seconds {
+                    write!(out, " ({:.6}ms)", seconds * 1000.0)?;
+                }
+                if let Some(bandwidth) = bandwidth {
+                    write!(out, " {bandwidth:.6} GB/s")?;
+                }
+
+                // Print page faults
+                if let Some(kb_per_fault) = kb_per_fault {
+                    write!(out, " PF: {:.4} ({kb_per_fault:.4} KB/fault)", avg.page_faults)?;
+                }
+                Ok(())
+            }
+            OutputFormat::Csv => {
+                let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{}",
+                    csv_field(label),
+                    self.num_tests,
+                    avg.time,
+                    optional(seconds.map(|seconds| seconds * 1000.0)),
+                    avg.bytes,
+                    optional(bandwidth),
+                    avg.page_faults,
+                    optional(kb_per_fault),
+                )
+            }
+            OutputFormat::Json => {
+                let optional = |value: Option<f64>| value.filter(|value| value.is_finite()).map_or("null".to_string(), |value| value.to_string());
+                out.write_all(b"{\"label\": ")?;
+                write_json_string(out, label)?;
+                writeln!(
+                    out,
+                    ", \"tests\": {}, \"ticks\": {}, \"ms\": {}, \"bytes\": {}, \"gb_per_second\": {}, \"page_faults\": {}, \"kb_per_fault\": {}}}",
+                    self.num_tests,
+                    avg.time,
+                    optional(seconds.map(|seconds| seconds * 1000.0)),
+                    avg.bytes,
+                    optional(bandwidth),
+                    avg.page_faults,
+                    optional(kb_per_fault),
+                )
             }
         }
-        
-        // Print page faults
-        if avg.page_faults > 0 {
-            print!(" PF: {:.4} ({:.4} KB/fault)", avg.page_faults, avg.bytes as f64 / (avg.page_faults as f64 * 1024.0));
-        }
-        
-        io::stdout().flush().unwrap();
     }
+
+    /// Columns of the CSV rows `write` produces.
+    pub const CSV_HEADER: &'static str = "label,tests,ticks,ms,bytes,gb_per_second,page_faults,kb_per_fault";
 }
 
 impl Add<RepetitionTestValue> for RepetitionTestValue {
@@ -107,12 +153,37 @@ pub struct RepetitionTestResult {
 
 impl RepetitionTestResult {
     pub fn print(&self, cpu_freq: u64) {
-        self.min.print("Min", cpu_freq);
-        println!();
-        self.max.print("Max", cpu_freq);
-        println!();
-        self.total.print("Avg", cpu_freq);
-        println!();
+        let mut out = io::stdout().lock();
+        self.write(&mut out, cpu_freq, OutputFormat::Human).unwrap();
+        out.flush().unwrap();
+    }
+
+    /// Writes the min, max and average rows, with a CSV header or as one JSON array.
+    pub fn write(&self, out: &mut impl Write, cpu_freq: u64, format: OutputFormat) -> io::Result<()> {
+        let rows = [("Min", &self.min), ("Max", &self.max), ("Avg", &self.total)];
+        match format {
+            OutputFormat::Human => {
+                for (label, value) in rows {
+                    value.write(out, label, cpu_freq, format)?;
+                    writeln!(out)?;
+                }
+            }
+            OutputFormat::Csv => {
+                writeln!(out, "{}", RepetitionTestValue::CSV_HEADER)?;
+                for (label, value) in rows {
+                    value.write(out, label, cpu_freq, format)?;
+                }
+            }
+            OutputFormat::Json => {
+                writeln!(out, "[")?;
+                for (index, (label, value)) in rows.into_iter().enumerate() {
+                    out.write_all(if index == 0 { b"  " } else { b", " })?;
+                    value.write(out, label, cpu_freq, format)?;
+                }
+                writeln!(out, "]")?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -134,6 +205,7 @@ pub struct RepetitionTester {
     test_start_time: u64,
     mode: TestMode,
     pub print_new_minimums: bool,
+    pub print_results: bool,
     block_count: u32,
     accumulated: RepetitionTestValue,
     result: RepetitionTestResult,
@@ -148,6 +220,7 @@ impl RepetitionTester {
             test_start_time: 0,
             mode: TestMode::Testing,
             print_new_minimums: true,
+            print_results: true,
             block_count: 0,
             accumulated: RepetitionTestValue::zero(),
             result: RepetitionTestResult::default(),
@@ -221,8 +294,10 @@ impl RepetitionTester {
             if current_time - self.test_start_time > self.try_for_time {
                 self.mode = TestMode::Completed;
 
-                print!("                                                          \r");
-                self.result.print(self.cpu_timer_freq);
+                if self.print_results {
+                    print!("                                                          \r");
+                    self.result.print(self.cpu_timer_freq);
+                }
             }
         }
         
@@ -240,21 +315,29 @@ impl RepetitionTester {
 
 pub struct RepetitionTesterBlock<'a> {
     tester: &'a mut RepetitionTester,
+    timer: TimerPair,
 }
 
 impl<'a> RepetitionTesterBlock<'a> {
     #[inline]
     pub fn new(tester: &'a mut RepetitionTester) -> Self {
-        tester.accumulated.time = tester.accumulated.time.wrapping_sub(read_cpu_timer());
+        Self::with_timer(tester, TimerPair::Rdtsc)
+    }
+
+    /// Times the block with `timer`, which is read closest to the timed code, inside the page
+    /// fault count.
+    #[inline]
+    pub fn with_timer(tester: &'a mut RepetitionTester, timer: TimerPair) -> Self {
         tester.accumulated.page_faults = tester.accumulated.page_faults.wrapping_sub(read_os_page_fault_count());
         tester.block_count += 1;
-        Self { tester }
+        tester.accumulated.time = tester.accumulated.time.wrapping_sub(timer.begin());
+        Self { tester, timer }
     }
 }
 
 impl Drop for RepetitionTesterBlock<'_> {
     fn drop(&mut self) {
-        self.tester.accumulated.time = self.tester.accumulated.time.wrapping_add(read_cpu_timer());
+        self.tester.accumulated.time = self.tester.accumulated.time.wrapping_add(self.timer.end());
         self.tester.accumulated.page_faults = self.tester.accumulated.page_faults.wrapping_add(read_os_page_fault_count());
     }
 }
@@ -264,5 +347,27 @@ macro_rules! test_block {
     ($tester:ident) => {
         let _block = $crate::repetition_tester::RepetitionTesterBlock::new($tester);
     };
+    ($tester:ident, $timer:expr) => {
+        let _block = $crate::repetition_tester::RepetitionTesterBlock::with_timer($tester, $timer);
+    };
 }
 pub use test_block;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_each_format() {
+        let value = RepetitionTestValue { num_tests: 2, time: 2000, bytes: 2048, page_faults: 2 };
+        let write = |format| {
+            let mut out = Vec::new();
+            value.write(&mut out, "Min", 1_000_000, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(write(OutputFormat::Human), format!("Min: 1000 (1.000000ms) {:.6} GB/s PF: 1 (1.0000 KB/fault)", 1024.0 / (1024.0 * 1024.0 * 1024.0) * 1000.0));
+        assert_eq!(write(OutputFormat::Csv), format!("Min,2,1000,1,1024,{},1,1\n", 1024.0 / (1024.0 * 1024.0 * 1024.0) * 1000.0));
+        assert!(write(OutputFormat::Json).starts_with(r#"{"label": "Min", "tests": 2, "ticks": 1000, "ms": 1, "bytes": 1024,"#));
+    }
+}
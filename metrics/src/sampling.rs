@@ -0,0 +1,193 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Called from the signal handler with the interrupted instruction pointer, so it may only do
+/// async-signal-safe things: atomics and thread-locals without destructors, no locks or allocation.
+pub type SampleHandler = fn(ip: u64);
+
+static HANDLER: AtomicUsize = AtomicUsize::new(0); // SampleHandler, 0 while no timer runs
+
+/// Interrupts the process with SIGPROF `hz` times per second of CPU time used by all its threads,
+/// and passes each interrupted instruction pointer to the handler. Stops when dropped.
+pub struct SampleTimer {
+    #[cfg(target_os = "linux")]
+    previous: libc::sigaction,
+    #[cfg(target_os = "linux")]
+    timer: libc::timer_t,
+}
+
+// The timer handle is only used by whoever holds the SampleTimer
+unsafe impl Send for SampleTimer {}
+
+impl SampleTimer {
+    /// Only one timer can run at a time.
+    pub fn start(hz: u32, handler: SampleHandler) -> io::Result<SampleTimer> {
+        if hz == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sampling rate must be above 0"));
+        }
+        if HANDLER.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a sample timer is already running"));
+        }
+        sys::start(hz).inspect_err(|_| HANDLER.store(0, Ordering::Release))
+    }
+}
+
+impl Drop for SampleTimer {
+    fn drop(&mut self) {
+        sys::stop(self);
+        HANDLER.store(0, Ordering::Release);
+    }
+}
+
+/// Module containing `ip` and the offset into it, which `addr2line -e <module>` understands.
+pub fn module_offset(ip: u64) -> Option<(String, u64)> {
+    sys::module_offset(ip)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CStr;
+    use std::sync::atomic::Ordering;
+    use std::{io, mem, ptr};
+
+    use super::{SampleHandler, SampleTimer, HANDLER};
+
+    extern "C" fn on_signal(_: libc::c_int, _: *mut libc::siginfo_t, context: *mut libc::c_void) {
+        let handler = HANDLER.load(Ordering::Acquire);
+        if handler != 0 && !context.is_null() {
+            let handler: SampleHandler = unsafe { mem::transmute(handler) };
+            handler(instruction_pointer(unsafe { &*context.cast::<libc::ucontext_t>() }));
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn instruction_pointer(context: &libc::ucontext_t) -> u64 {
+        context.uc_mcontext.gregs[libc::REG_RIP as usize] as u64
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn instruction_pointer(context: &libc::ucontext_t) -> u64 {
+        context.uc_mcontext.pc
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn instruction_pointer(_: &libc::ucontext_t) -> u64 {
+        0
+    }
+
+    fn set_interval(timer: libc::timer_t, hz: u32) -> io::Result<()> {
+        let period = if hz == 0 { 0 } else { (1_000_000_000 / hz as u64).max(1) };
+        let interval = libc::timespec { tv_sec: (period / 1_000_000_000) as libc::time_t, tv_nsec: (period % 1_000_000_000) as libc::c_long };
+        let spec = libc::itimerspec { it_interval: interval, it_value: interval };
+        if unsafe { libc::timer_settime(timer, 0, &spec, ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn start(hz: u32) -> io::Result<SampleTimer> {
+        let mut timer = SampleTimer { previous: unsafe { mem::zeroed() }, timer: ptr::null_mut() };
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as usize;
+            // Interrupted system calls carry on instead of failing with EINTR
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGPROF, &action, &mut timer.previous) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // Counts CPU time of all threads, and signals the process rather than a thread
+        let mut event: libc::sigevent = unsafe { mem::zeroed() };
+        event.sigev_notify = libc::SIGEV_SIGNAL;
+        event.sigev_signo = libc::SIGPROF;
+        let created = unsafe { libc::timer_create(libc::CLOCK_PROCESS_CPUTIME_ID, &mut event, &mut timer.timer) };
+        let armed = if created < 0 { Err(io::Error::last_os_error()) } else { set_interval(timer.timer, hz) };
+        if let Err(e) = armed {
+            if created == 0 {
+                unsafe { libc::timer_delete(timer.timer) };
+            }
+            restore_action(&timer.previous);
+            return Err(e);
+        }
+        Ok(timer)
+    }
+
+    /// Ignoring SIGPROF first discards one the deleted timer left pending, which the previous
+    /// action, usually the default of terminating the process, would otherwise receive.
+    fn restore_action(previous: &libc::sigaction) {
+        unsafe {
+            let mut ignore: libc::sigaction = mem::zeroed();
+            ignore.sa_sigaction = libc::SIG_IGN;
+            libc::sigemptyset(&mut ignore.sa_mask);
+            libc::sigaction(libc::SIGPROF, &ignore, ptr::null_mut());
+            libc::sigaction(libc::SIGPROF, previous, ptr::null_mut());
+        }
+    }
+
+    pub fn stop(timer: &SampleTimer) {
+        unsafe { libc::timer_delete(timer.timer) };
+        restore_action(&timer.previous);
+    }
+
+    pub fn module_offset(ip: u64) -> Option<(String, u64)> {
+        let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+        if unsafe { libc::dladdr(ip as *const libc::c_void, &mut info) } == 0 || info.dli_fname.is_null() {
+            return None;
+        }
+        let module = unsafe { CStr::from_ptr(info.dli_fname) }.to_string_lossy().into_owned();
+        Some((module, ip - info.dli_fbase as u64))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    use super::SampleTimer;
+
+    pub fn start(_: u32) -> io::Result<SampleTimer> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "SIGPROF sampling is Linux only"))
+    }
+
+    pub fn stop(_: &SampleTimer) {}
+
+    pub fn module_offset(_: u64) -> Option<(String, u64)> {
+        None
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::time::{Duration, Instant};
+
+    static SAMPLES: AtomicU64 = AtomicU64::new(0);
+    static LAST_IP: AtomicU64 = AtomicU64::new(0);
+
+    fn count_sample(ip: u64) {
+        SAMPLES.fetch_add(1, Ordering::Relaxed);
+        LAST_IP.store(ip, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn samples_busy_loop() {
+        let timer = SampleTimer::start(1000, count_sample).unwrap();
+        assert_eq!(SampleTimer::start(1000, count_sample).err().map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+
+        let start = Instant::now();
+        let mut x = 0u64;
+        while SAMPLES.load(Ordering::Relaxed) < 5 && start.elapsed() < Duration::from_secs(5) {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(1));
+        }
+        drop(timer);
+
+        assert!(SAMPLES.load(Ordering::Relaxed) >= 5);
+        assert!(module_offset(LAST_IP.load(Ordering::Relaxed)).is_some());
+
+        // Periods of a second or more need whole seconds in the timespec
+        drop(SampleTimer::start(1, count_sample).unwrap());
+    }
+}
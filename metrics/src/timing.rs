@@ -1,6 +1,12 @@
+use std::arch::asm;
+use std::arch::x86_64::{__cpuid, __get_cpuid_max};
 use std::num::NonZeroU64;
+use std::sync::OnceLock;
+
+#[cfg(windows)]
 use windows_sys::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
 
+#[cfg(windows)]
 pub fn get_os_timer_freq() -> u64 {
     let mut freq = 0;
     unsafe {
@@ -9,6 +15,7 @@ pub fn get_os_timer_freq() -> u64 {
     freq as u64
 }
 
+#[cfg(windows)]
 pub fn read_os_timer() -> u64 {
     let mut count = 0;
     unsafe {
@@ -17,11 +24,145 @@ pub fn read_os_timer() -> u64 {
     count as u64
 }
 
+/// Nanoseconds.
+#[cfg(unix)]
+pub fn get_os_timer_freq() -> u64 {
+    1_000_000_000
+}
+
+#[cfg(unix)]
+pub fn read_os_timer() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
 #[inline]
 pub fn read_cpu_timer() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
+// A bare rdtsc can execute before earlier instructions finish and let later ones start first, so
+// work moves across the edges of what it times. The pairs below fence it: the begin read waits for
+// everything before it and holds back everything after, the end read waits for everything timed.
+// The compiler keeps memory accesses on their side of each read, but may still move arithmetic on
+// registers across it.
+
+/// `lfence; rdtsc; lfence`: waits for earlier instructions to complete and keeps later ones from
+/// starting before the read.
+#[inline(always)]
+pub fn read_cpu_timer_lfence_begin() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("lfence", "rdtsc", "lfence", out("eax") low, out("edx") high, options(nostack, preserves_flags)) };
+    (high as u64) << 32 | low as u64
+}
+
+/// `rdtscp; lfence`: rdtscp waits for earlier instructions to complete, the fence keeps later ones
+/// from starting before it. Needs the RDTSCP extension, which every x86-64 CPU of the last decade
+/// has.
+#[inline(always)]
+pub fn read_cpu_timer_lfence_end() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtscp", "lfence", out("eax") low, out("edx") high, out("ecx") _, options(nostack, preserves_flags)) };
+    (high as u64) << 32 | low as u64
+}
+
+/// `cpuid; rdtsc`: cpuid is fully serializing, it also drains the store buffer. Costs a few
+/// hundred cycles, and a VM exit under most hypervisors.
+#[inline(always)]
+pub fn read_cpu_timer_cpuid_begin() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        // rbx belongs to LLVM, so cpuid's write to it is saved and restored by hand
+        asm!(
+            "mov {saved:r}, rbx",
+            "xor eax, eax",
+            "xor ecx, ecx",
+            "cpuid",
+            "mov rbx, {saved:r}",
+            "rdtsc",
+            saved = out(reg) _,
+            out("eax") low,
+            out("ecx") _,
+            out("edx") high,
+            options(nostack),
+        )
+    };
+    (high as u64) << 32 | low as u64
+}
+
+/// `rdtscp; cpuid`: the read waits for the timed instructions, then cpuid holds back everything
+/// after it.
+#[inline(always)]
+pub fn read_cpu_timer_cpuid_end() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+            "rdtscp",
+            "mov {low:e}, eax",
+            "mov {high:e}, edx",
+            "mov {saved:r}, rbx",
+            "xor eax, eax",
+            "xor ecx, ecx",
+            "cpuid",
+            "mov rbx, {saved:r}",
+            low = out(reg) low,
+            high = out(reg) high,
+            saved = out(reg) _,
+            out("eax") _,
+            out("ecx") _,
+            out("edx") _,
+            options(nostack),
+        )
+    };
+    (high as u64) << 32 | low as u64
+}
+
+/// Which instructions read the timer at the start and end of a measurement.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimerPair {
+    /// Bare rdtsc at both ends. Cheapest, but only good for spans long enough that a few hundred
+    /// cycles of reordering at the edges do not matter.
+    #[default]
+    Rdtsc,
+    /// `lfence; rdtsc; lfence` to begin and `rdtscp; lfence` to end.
+    Lfence,
+    /// `cpuid; rdtsc` to begin and `rdtscp; cpuid` to end.
+    Cpuid,
+}
+
+impl TimerPair {
+    pub const ALL: [TimerPair; 3] = [TimerPair::Rdtsc, TimerPair::Lfence, TimerPair::Cpuid];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TimerPair::Rdtsc => "rdtsc",
+            TimerPair::Lfence => "lfence",
+            TimerPair::Cpuid => "cpuid",
+        }
+    }
+
+    #[inline(always)]
+    pub fn begin(self) -> u64 {
+        match self {
+            TimerPair::Rdtsc => read_cpu_timer(),
+            TimerPair::Lfence => read_cpu_timer_lfence_begin(),
+            TimerPair::Cpuid => read_cpu_timer_cpuid_begin(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn end(self) -> u64 {
+        match self {
+            TimerPair::Rdtsc => read_cpu_timer(),
+            TimerPair::Lfence => read_cpu_timer_lfence_end(),
+            TimerPair::Cpuid => read_cpu_timer_cpuid_end(),
+        }
+    }
+}
+
 pub fn estimate_cpu_frequency(millis_to_wait: u64) -> u64 {
     let os_freq = get_os_timer_freq();
 
@@ -47,3 +188,240 @@ pub fn estimate_cpu_frequency(millis_to_wait: u64) -> u64 {
 pub fn cpu_time_to_seconds(cpu_time: u64, cpu_freq: NonZeroU64) -> f64 {
     cpu_time as f64 / cpu_freq.get() as f64
 }
+
+/// Where a TSC frequency came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrequencySource {
+    /// CPUID leaf 0x15: the crystal clock and its ratio to the TSC.
+    CpuidTsc,
+    /// The kernel's own TSC calibration.
+    Kernel,
+    /// CPUID leaf 0x16: the processor base frequency, which is the TSC rate on most Intel parts.
+    CpuidBase,
+    /// Timed against the OS timer.
+    Calibrated,
+}
+
+impl FrequencySource {
+    pub fn name(self) -> &'static str {
+        match self {
+            FrequencySource::CpuidTsc => "cpuid 0x15",
+            FrequencySource::Kernel => "kernel",
+            FrequencySource::CpuidBase => "cpuid 0x16",
+            FrequencySource::Calibrated => "calibrated",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CpuFrequency {
+    pub hz: u64,
+    pub source: FrequencySource,
+    /// Expected error as a fraction of `hz`.
+    pub error: f64,
+}
+
+/// The TSC frequency and whether the TSC can be trusted as a clock. `None` where it could not be
+/// told.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TscInfo {
+    pub frequency: CpuFrequency,
+    /// Ticks at a constant rate in every P-, C- and T-state, so it never stops or slows down.
+    pub invariant: Option<bool>,
+    /// Ticks at a constant rate whatever the core clock, but may stop in deep sleep states.
+    pub constant: Option<bool>,
+}
+
+/// Calibrations that spread more than this are reported as unreliable.
+const MAX_CALIBRATION_ERROR: f64 = 0.01;
+
+impl TscInfo {
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.constant == Some(false) {
+            warnings.push("the TSC is not constant, it follows the core clock so cycles do not convert to time".to_string());
+        } else if self.invariant == Some(false) {
+            warnings.push("the TSC is not invariant, it may stop while cores sleep".to_string());
+        }
+        if self.frequency.error > MAX_CALIBRATION_ERROR {
+            warnings.push(format!("the CPU frequency calibration varied by {:.1}%", self.frequency.error * 100.0));
+        }
+        warnings
+    }
+}
+
+const CALIBRATION_SAMPLES: usize = 5;
+const CALIBRATION_MILLIS: u64 = 20;
+
+/// Finds the TSC frequency once and caches it: from CPUID or the kernel when they give it, which
+/// costs nothing, else by calibrating against the OS timer for about 100ms.
+pub fn tsc_info() -> TscInfo {
+    static INFO: OnceLock<TscInfo> = OnceLock::new();
+    *INFO.get_or_init(|| {
+        let frequency = cpuid_tsc_frequency()
+            .or_else(kernel_tsc_frequency)
+            .or_else(cpuid_base_frequency)
+            .unwrap_or_else(|| calibrate_cpu_frequency(CALIBRATION_SAMPLES, CALIBRATION_MILLIS));
+        let (invariant, constant) = tsc_flags();
+        TscInfo { frequency, invariant, constant }
+    })
+}
+
+/// The TSC frequency in Hz, see `tsc_info`.
+pub fn cpu_frequency() -> u64 {
+    tsc_info().frequency.hz
+}
+
+fn max_cpuid_leaf(base: u32) -> u32 {
+    __get_cpuid_max(base).0
+}
+
+pub fn cpuid_tsc_frequency() -> Option<CpuFrequency> {
+    if max_cpuid_leaf(0) < 0x15 {
+        return None;
+    }
+    let leaf = __cpuid(0x15);
+    // Denominator, numerator and crystal Hz. Some parts leave the crystal out, and then only the
+    // base frequency is left to go on.
+    let hz = tsc_from_crystal(leaf.eax, leaf.ebx, leaf.ecx)?;
+    Some(CpuFrequency { hz, source: FrequencySource::CpuidTsc, error: 0.0 })
+}
+
+fn tsc_from_crystal(denominator: u32, numerator: u32, crystal_hz: u32) -> Option<u64> {
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;
+    }
+    Some(crystal_hz as u64 * numerator as u64 / denominator as u64)
+}
+
+pub fn cpuid_base_frequency() -> Option<CpuFrequency> {
+    if max_cpuid_leaf(0) < 0x16 {
+        return None;
+    }
+    let base_mhz = __cpuid(0x16).eax & 0xffff;
+    if base_mhz == 0 {
+        return None;
+    }
+    let hz = base_mhz as u64 * 1_000_000;
+    // Whole MHz
+    Some(CpuFrequency { hz, source: FrequencySource::CpuidBase, error: 0.5e6 / hz as f64 })
+}
+
+#[cfg(target_os = "linux")]
+pub fn kernel_tsc_frequency() -> Option<CpuFrequency> {
+    // Some kernels export tsc_khz directly, the rest only through the perf clock conversion
+    if let Some(khz) = std::fs::read_to_string("/sys/devices/system/cpu/cpu0/tsc_freq_khz").ok().and_then(|text| text.trim().parse::<u64>().ok()) {
+        if khz > 0 {
+            return Some(CpuFrequency { hz: khz * 1000, source: FrequencySource::Kernel, error: 0.5 / khz as f64 });
+        }
+    }
+    let (mult, shift) = crate::perf::tsc_conversion().ok()?;
+    let hz = tsc_from_conversion(mult, shift);
+    (hz > 0).then(|| CpuFrequency { hz, source: FrequencySource::Kernel, error: 1.0 / mult as f64 })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn kernel_tsc_frequency() -> Option<CpuFrequency> {
+    None
+}
+
+/// Inverts `ns = ticks * mult >> shift`.
+fn tsc_from_conversion(mult: u32, shift: u16) -> u64 {
+    if mult == 0 || shift >= 64 {
+        return 0;
+    }
+    ((1_000_000_000u128 << shift) / mult as u128) as u64
+}
+
+/// Times `samples` waits of `millis_per_sample` each and takes the median, so a wait that was
+/// preempted or migrated does not skew it. The error is the median deviation from it, and never
+/// less than one OS timer tick per wait.
+pub fn calibrate_cpu_frequency(samples: usize, millis_per_sample: u64) -> CpuFrequency {
+    let mut estimates = (0..samples.max(1)).map(|_| estimate_cpu_frequency(millis_per_sample)).collect::<Vec<_>>();
+    let (hz, spread) = median_and_spread(&mut estimates);
+    let os_ticks = (millis_per_sample * get_os_timer_freq() / 1000).max(1);
+    CpuFrequency { hz, source: FrequencySource::Calibrated, error: spread.max(1.0 / os_ticks as f64) }
+}
+
+/// The median and the median absolute deviation from it, relative to the median.
+fn median_and_spread(values: &mut [u64]) -> (u64, f64) {
+    values.sort_unstable();
+    let median = values[values.len() / 2];
+    let mut deviations = values.iter().map(|&value| value.abs_diff(median)).collect::<Vec<_>>();
+    deviations.sort_unstable();
+    let spread = if median > 0 { deviations[deviations.len() / 2] as f64 / median as f64 } else { 0.0 };
+    (median, spread)
+}
+
+/// Invariant and constant TSC, from CPUID 0x80000007 EDX bit 8, which implies both, and on Linux
+/// from the kernel's constant_tsc and nonstop_tsc flags. Hypervisors often hide the CPUID bit while
+/// the kernel still knows.
+fn tsc_flags() -> (Option<bool>, Option<bool>) {
+    let cpuid_invariant = (max_cpuid_leaf(0x8000_0000) >= 0x8000_0007).then(|| __cpuid(0x8000_0007).edx & (1 << 8) != 0);
+    let (kernel_invariant, kernel_constant) = kernel_tsc_flags();
+
+    let invariant = match (cpuid_invariant, kernel_invariant) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (None, None) => None,
+        _ => Some(false),
+    };
+    let constant = if invariant == Some(true) { Some(true) } else { kernel_constant };
+    (invariant, constant)
+}
+
+#[cfg(target_os = "linux")]
+fn kernel_tsc_flags() -> (Option<bool>, Option<bool>) {
+    let Ok(cpuinfo) = std::fs::read_to_string("/proc/cpuinfo") else {
+        return (None, None);
+    };
+    let Some(flags) = cpuinfo.lines().find_map(|line| line.strip_prefix("flags")) else {
+        return (None, None);
+    };
+    let has = |name| flags.split_whitespace().any(|flag| flag == name);
+    let constant = has("constant_tsc");
+    (Some(constant && has("nonstop_tsc")), Some(constant))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_tsc_flags() -> (Option<bool>, Option<bool>) {
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequency_math() {
+        // A 24MHz crystal at 176/2, and the kernel's scale for a 2.5GHz TSC
+        assert_eq!(tsc_from_crystal(2, 176, 24_000_000), Some(2_112_000_000));
+        assert_eq!(tsc_from_crystal(2, 176, 0), None);
+        assert_eq!(tsc_from_conversion(6710886, 24), 2_500_000_149);
+
+        let mut samples = [2_000_000_000, 2_000_100_000, 1_999_900_000, 900_000_000, 2_000_000_000];
+        let (median, spread) = median_and_spread(&mut samples);
+        assert_eq!(median, 2_000_000_000);
+        assert_eq!(spread, 100_000.0 / 2e9);
+    }
+
+    #[test]
+    fn tsc_info_is_usable() {
+        let info = tsc_info();
+        assert!(info.frequency.hz > 0);
+        assert_eq!(tsc_info(), info);
+
+        let slow = TscInfo { constant: Some(false), frequency: CpuFrequency { error: 0.05, ..info.frequency }, ..info };
+        assert_eq!(slow.warnings().len(), 2);
+    }
+
+    #[test]
+    fn timer_pairs_count_forward() {
+        for timer in TimerPair::ALL {
+            let begin = timer.begin();
+            let middle = timer.end();
+            let end = timer.end();
+            assert!(begin <= middle && middle <= end, "{} went backwards", timer.name());
+            assert!(end - begin < cpu_frequency(), "{} took a second", timer.name());
+        }
+    }
+}
@@ -32,6 +32,7 @@ fn main() -> std::io::Result<()> {
             //testing::bandwidth_test_loop(size, cpu_freq, &filename);
             //testing::asm_test_loop(size, cpu_freq, &filename, WRITE_ASM_TESTS);
             //testing::branch_predictor_test_loop(size, cpu_freq, &filename);
+            //testing::timer_test_loop(cpu_freq);
             //testing::asm_test_loop(size, cpu_freq, &filename, WRITE_PORT_TESTS);
             testing::asm_test_loop(size, cpu_freq, &filename, READ_WIDTH_TESTS);
         } else {
@@ -13,9 +13,12 @@ pub use write_tests::asm_test_loop;
 pub mod branch_predictor_tests;
 pub mod simd_tests;
 pub mod cache_tests;
+pub mod timer_tests;
 
 #[allow(unused_imports)]
 pub use branch_predictor_tests::branch_predictor_test_loop;
+#[allow(unused_imports)]
+pub use timer_tests::timer_test_loop;
 
 #[derive(Copy, Clone, PartialEq, Sequence)]
 #[repr(u8)]
@@ -0,0 +1,57 @@
+use std::arch::asm;
+
+use metrics::repetition_tester::RepetitionTester;
+use metrics::test_block;
+use metrics::timing::TimerPair;
+use crate::testing::TRY_FOR_SECONDS;
+
+// Each multiply waits for the one before, so a chain of n takes n times the imul latency (3 cycles on
+// recent cores) however wide the core is. A bare rdtsc at the end does not wait for the chain, so
+// short chains come out too fast; the fenced pairs show the full latency plus their own cost.
+const CHAIN_LENGTHS: [u64; 4] = [0, 8, 64, 512];
+
+#[inline(always)]
+fn dependent_multiplies(count: u64) {
+    unsafe {
+        asm!(
+            r#"
+            test {count}, {count}
+            jz 3f
+        2:
+            imul {value}, {value}
+            dec {count}
+            jnz 2b
+        3:"#,
+            count = inout(reg) count => _,
+            value = inout(reg) 3u64 => _,
+            options(nomem, nostack),
+        );
+    }
+}
+
+#[allow(dead_code)]
+pub fn timer_test_loop(cpu_freq: u64) {
+    // Bytes stand in for multiplies, so the byte count checks that every block ran the whole chain
+    let mut testers = CHAIN_LENGTHS.map(|count| [RepetitionTester::new(count, cpu_freq); TimerPair::ALL.len()]);
+
+    'test_loop: loop {
+        for (&count, testers) in CHAIN_LENGTHS.iter().zip(testers.iter_mut()) {
+            for (tester, timer) in testers.iter_mut().zip(TimerPair::ALL) {
+                print!("\n--- {count} dependent imuls, {} timer ---\n", timer.name());
+
+                tester.new_test_wave(count, cpu_freq, TRY_FOR_SECONDS);
+                while tester.testing() {
+                    {
+                        test_block!(tester, timer);
+                        dependent_multiplies(count);
+                    }
+                    tester.count_bytes(count);
+                }
+
+                if tester.has_error() {
+                    break 'test_loop;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,7 @@
+#!/bin/sh
+# Runs the haversine tests with the profiler stubbed out and compiled in, since each
+# configuration builds different code.
+set -e
+cd "$(dirname "$0")/.."
+cargo test -p haversine "$@"
+cargo test -p haversine --features profile "$@"